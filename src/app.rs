@@ -0,0 +1,156 @@
+use glium as gl;
+use glium::DisplayBuild;
+use glium::backend::glutin_backend::GlutinFacade;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::world::*;
+use super::Millis;
+
+/// Owns the display and the `World` and drives them with a fixed
+/// update timestep, drawing as often as possible (or as allowed by the
+/// frame limit) in between.
+///
+/// ```ignore
+/// let mut app = App::new(glutin::WindowBuilder::new().with_depth_buffer(24));
+/// app.on_setup(|display, world| {
+///   world.resources.load_obj(display, "cube", "cube.obj").unwrap();
+/// });
+/// app.run();
+/// ```
+pub struct App {
+  pub display: GlutinFacade,
+  pub world:   World,
+
+  update_step:           Duration,
+  max_updates_per_frame: u32,
+  frame_limit:           Option<Duration>,
+
+  setup_hook:   Option<Box<FnMut(&GlutinFacade, &mut World)>>,
+  update_hooks: Vec<Box<FnMut(&mut World, Millis)>>,
+  frame_hooks:  Vec<Box<FnMut(&mut World, f32)>>,
+}
+
+impl App {
+  pub fn new(window: gl::glutin::WindowBuilder) -> Self {
+    let display = window.build_glium().unwrap();
+    let world = World::with_display(&display);
+
+    App {
+      display: display,
+      world:   world,
+
+      update_step:           Duration::new(0, 1000000000/60),
+      max_updates_per_frame: 10,
+      frame_limit:           None,
+
+      setup_hook:   None,
+      update_hooks: Vec::new(),
+      frame_hooks:  Vec::new(),
+    }
+  }
+
+  /// Number of fixed update steps per second.
+  pub fn set_update_rate(&mut self, updates_per_second: u32) {
+    assert!(updates_per_second > 0);
+    self.update_step = Duration::new(0, 1000000000/updates_per_second);
+  }
+
+  /// If the simulation falls behind by more than `max` update steps the
+  /// remaining time is dropped instead of trying to catch up.
+  pub fn set_max_updates_per_frame(&mut self, max: u32) {
+    assert!(max > 0);
+    self.max_updates_per_frame = max;
+  }
+
+  /// Limits the number of frames drawn per second. `None` draws as fast
+  /// as possible.
+  pub fn set_frame_limit(&mut self, frames_per_second: Option<u32>) {
+    self.frame_limit = frames_per_second.map(|fps| {
+      assert!(fps > 0);
+      Duration::new(0, 1000000000/fps)
+    });
+  }
+
+  /// Called once when `run` starts, e.g. to load resources and create
+  /// entities.
+  pub fn on_setup<F>(&mut self, f: F)
+    where F: FnMut(&GlutinFacade, &mut World) + 'static {
+    self.setup_hook = Some(Box::new(f));
+  }
+
+  /// Called before every fixed update step.
+  pub fn on_update<F>(&mut self, f: F)
+    where F: FnMut(&mut World, Millis) + 'static {
+    self.update_hooks.push(Box::new(f));
+  }
+
+  /// Called before every drawn frame with the interpolation factor.
+  pub fn on_frame<F>(&mut self, f: F)
+    where F: FnMut(&mut World, f32) + 'static {
+    self.frame_hooks.push(Box::new(f));
+  }
+
+  /// Stops the main loop after the current frame.
+  pub fn quit(&mut self) {
+    self.world.quit();
+  }
+
+  /// Runs the main loop until the window gets closed or `World::quit`
+  /// is called.
+  pub fn run(&mut self) {
+    if let Some(mut setup) = self.setup_hook.take() {
+      setup(&self.display, &mut self.world);
+    }
+
+    let mut previous = Instant::now();
+    let mut lag = Duration::new(0, 0);
+
+    while !self.world.should_quit() {
+      let frame_start = Instant::now();
+      lag += frame_start - previous;
+      previous = frame_start;
+
+      let mut updates = 0;
+      while lag >= self.update_step {
+        if updates == self.max_updates_per_frame {
+          println!("Can't keep up, dropping {} update steps",
+                   Millis::from(lag) / Millis::from(self.update_step));
+          lag = Duration::new(0, 0);
+          break;
+        }
+
+        let delta = self.update_step.into();
+        for hook in self.update_hooks.iter_mut() {
+          hook(&mut self.world, delta);
+        }
+        self.world.update(delta);
+
+        lag -= self.update_step;
+        updates += 1;
+      }
+
+      self.world.handle_events(self.display.poll_events());
+
+      // Fraction of an update step not yet simulated
+      let alpha = Millis::from(lag) / Millis::from(self.update_step);
+      for hook in self.frame_hooks.iter_mut() {
+        hook(&mut self.world, alpha);
+      }
+
+      let mut target = self.display.draw();
+      self.world.draw(&mut target, alpha);
+      target.finish().unwrap();
+
+      if let Some(limit) = self.frame_limit {
+        let elapsed = frame_start.elapsed();
+        if elapsed < limit {
+          thread::sleep(limit - elapsed);
+        }
+      }
+    }
+
+    println!("Shutting down");
+  }
+}
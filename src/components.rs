@@ -3,75 +3,186 @@ use std::f32::consts;
 
 use super::*;
 
-pub type EntityId = u32;
+use std::fmt;
 
-const MAX_ENTITIES: usize = 4096;
+/// Handle to an entity. The generation gets bumped every time an index
+/// is recycled, so stale handles can be detected via `is_alive`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EntityId {
+  index:      u32,
+  generation: u32,
+}
+
+impl EntityId {
+  pub fn index(&self) -> u32 {
+    self.index
+  }
+
+  pub fn generation(&self) -> u32 {
+    self.generation
+  }
+}
 
-bitflags! {
-  // TODO: Add a macro for all of these
-  pub flags ComponentFlags: u64 {
-    const FLAG_NONE     = 1 << 1,
-    const FLAG_POSITION = 1 << 3,
-    const FLAG_SCALE    = 1 << 4,
-    const FLAG_ROTATION = 1 << 5,
-    const FLAG_VELOCITY = 1 << 6,
-    const FLAG_GEOMETRY = 1 << 7,
-    const FLAG_PICKABLE = 1 << 8,
-    const FLAG_CAMERA   = 1 << 9,
-    const FLAG_BOB      = 1 << 10,
+impl fmt::Display for EntityId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}v{}", self.index, self.generation)
   }
 }
 
-impl Default for ComponentFlags {
-  fn default() -> Self { FLAG_NONE }
+/// Set of component types, one bit per type. Bits past the built-in
+/// flags are handed out by `EntityManager::register`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ComponentFlags {
+  bits: u64,
 }
 
-pub struct EntityArray<T: Default>([T; MAX_ENTITIES]);
+// TODO: Add a macro for all of these
+// Set on every live entity, cleared when the entity gets deleted
+pub const FLAG_NONE: ComponentFlags      = ComponentFlags { bits: 1 << 1 };
+pub const FLAG_TRANSFORM: ComponentFlags = ComponentFlags { bits: 1 << 3 };
+pub const FLAG_VELOCITY: ComponentFlags  = ComponentFlags { bits: 1 << 6 };
+pub const FLAG_GEOMETRY: ComponentFlags  = ComponentFlags { bits: 1 << 7 };
+pub const FLAG_PICKABLE: ComponentFlags  = ComponentFlags { bits: 1 << 8 };
+pub const FLAG_CAMERA: ComponentFlags    = ComponentFlags { bits: 1 << 9 };
+pub const FLAG_BOB: ComponentFlags       = ComponentFlags { bits: 1 << 10 };
+pub const FLAG_PARENT: ComponentFlags    = ComponentFlags { bits: 1 << 11 };
+pub const FLAG_WORLD_TRANSFORM: ComponentFlags = ComponentFlags { bits: 1 << 12 };
+pub const FLAG_LIGHT: ComponentFlags     = ComponentFlags { bits: 1 << 13 };
+pub const FLAG_SHADOWS: ComponentFlags   = ComponentFlags { bits: 1 << 14 };
 
-impl<T: Default> Default for EntityArray<T> {
-  fn default() -> Self {
-    let mut arr: [T; MAX_ENTITIES];
-    unsafe {
-      use std::{ptr, mem};
-      arr = mem::uninitialized();
-      for elem in &mut arr[..] {
-        ptr::write(elem, Default::default())
-      }
-    }
-    EntityArray(arr)
+impl ComponentFlags {
+  pub fn empty() -> Self {
+    ComponentFlags { bits: 0 }
   }
-}
 
-pub type ComponentArray<T> = EntityArray<T>;
-pub type FlagsArray = EntityArray<ComponentFlags>;
+  /// The flag with only `bit` set.
+  pub fn from_bit(bit: u32) -> Self {
+    assert!(bit < 64, "Component flag bit {} out of range", bit);
+    ComponentFlags { bits: 1 << bit }
+  }
 
-use std::ops::{Index, IndexMut, RangeFull};
+  pub fn bits(&self) -> u64 {
+    self.bits
+  }
 
-impl<T: Default> Index<EntityId> for EntityArray<T> {
-  type Output = T;
-  fn index(&self, entity: EntityId) -> &Self::Output {
-    self.0.index(entity as usize)
+  pub fn is_empty(&self) -> bool {
+    self.bits == 0
+  }
+
+  /// Whether all flags of `other` are set.
+  pub fn contains(&self, other: ComponentFlags) -> bool {
+    self.bits & other.bits == other.bits
+  }
+
+  /// Whether any flag of `other` is set.
+  pub fn intersects(&self, other: ComponentFlags) -> bool {
+    self.bits & other.bits != 0
+  }
+
+  pub fn insert(&mut self, other: ComponentFlags) {
+    self.bits |= other.bits;
+  }
+
+  pub fn remove(&mut self, other: ComponentFlags) {
+    self.bits &= !other.bits;
+  }
+}
+
+impl BitOr for ComponentFlags {
+  type Output = ComponentFlags;
+  fn bitor(self, other: ComponentFlags) -> ComponentFlags {
+    ComponentFlags { bits: self.bits | other.bits }
   }
 }
 
-impl<T: Default> IndexMut<EntityId> for EntityArray<T> {
-    fn index_mut<'a>(&'a mut self, entity: EntityId) -> &'a mut T {
-      self.0.index_mut(entity as usize)
+impl BitAnd for ComponentFlags {
+  type Output = ComponentFlags;
+  fn bitand(self, other: ComponentFlags) -> ComponentFlags {
+    ComponentFlags { bits: self.bits & other.bits }
+  }
+}
+
+use std::ops::{Index, IndexMut, BitOr, BitAnd};
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+/// Allocates entity handles and stores the component flags of every
+/// entity. Indexing with a stale handle panics.
+#[derive(Default)]
+pub struct Entities {
+  highest_index: u32,
+  free_indices:  Vec<u32>,
+  flags:         EntityArray<ComponentFlags>,
+  generations:   EntityArray<u32>,
+}
+
+impl Entities {
+  fn create(&mut self) -> EntityId {
+    // Hand out previously deleted indices first
+    let index = match self.free_indices.pop() {
+      Some(index) => index,
+      None => {
+        self.highest_index += 1;
+        self.flags.reserve_index(self.highest_index);
+        self.generations.reserve_index(self.highest_index);
+        self.highest_index
+      }
+    };
+    self.flags[index] = FLAG_NONE;
+    EntityId {
+      index: index,
+      generation: self.generations[index],
+    }
+  }
+
+  fn destroy(&mut self, entity: EntityId) {
+    assert!(self.is_alive(entity), "Entity {} isn't alive", entity);
+    let index = entity.index;
+    self.flags[index] = ComponentFlags::empty();
+    self.generations[index] = self.generations[index].wrapping_add(1);
+    self.free_indices.push(entity.index);
+  }
+
+  pub fn is_alive(&self, entity: EntityId) -> bool {
+    let index = entity.index;
+    (index as usize) < self.flags.len()
+      && self.generations[index] == entity.generation
+      && self.flags[index].contains(FLAG_NONE)
+  }
+
+  /// Returns the handle of the live entity stored at `index`, if any.
+  pub fn get(&self, index: u32) -> Option<EntityId> {
+    if (index as usize) < self.flags.len() && self.flags[index].contains(FLAG_NONE) {
+      Some(EntityId {
+        index: index,
+        generation: self.generations[index],
+      })
+    } else {
+      None
     }
+  }
 }
 
-impl<'a, T: Default> Index<RangeFull> for EntityArray<T> {
-  type Output = [T];
-  fn index(&self, _idx: RangeFull) -> &Self::Output {
-    self.0.index(..)
+impl Index<EntityId> for Entities {
+  type Output = ComponentFlags;
+  fn index(&self, entity: EntityId) -> &Self::Output {
+    assert!(self.is_alive(entity), "Entity {} isn't alive", entity);
+    &self.flags[entity]
   }
 }
 
+impl IndexMut<EntityId> for Entities {
+  fn index_mut(&mut self, entity: EntityId) -> &mut ComponentFlags {
+    assert!(self.is_alive(entity), "Entity {} isn't alive", entity);
+    &mut self.flags[entity]
+  }
+}
 
 pub struct EntityIterator<'a> {
   // TODO: use a range for `idx`
-  idx: EntityId,
-  entities: &'a [ComponentFlags],
+  idx: u32,
+  entities: &'a Entities,
   flags: ComponentFlags,
 }
 
@@ -79,14 +190,12 @@ impl<'a> Iterator for EntityIterator<'a> {
   type Item = EntityId;
   fn next(&mut self) -> Option<Self::Item> {
     // TODO: Rewrite via iterators
-    while (self.idx as usize) < self.entities.len() {
-      if self.entities[self.idx as usize].contains(self.flags) {
-        let idx = self.idx;
-        self.idx += 1;
-        return Some(idx)
-      } else {
-        self.idx += 1;
-        continue;
+    // Index 0 is never handed out, so `flags` is empty without entities
+    while (self.idx as usize) < self.entities.flags.len() {
+      let idx = self.idx;
+      self.idx += 1;
+      if self.entities.flags[idx].contains(self.flags) {
+        return self.entities.get(idx)
       }
     }
     None
@@ -197,8 +306,9 @@ impl Default for Geometry {
   }
 }
 
+/// Holds the ID written into the picking buffer (the entity's index).
 #[derive(Debug, Copy, Clone)]
-pub struct Pickable(pub EntityId);
+pub struct Pickable(pub u32);
 
 impl Default for Pickable {
   fn default() -> Self { Pickable(0) }
@@ -219,78 +329,287 @@ impl Default for Camera {
   }
 }
 
-#[derive(Default)]
-pub struct EntityManager {
-  highest_id:     EntityId,
+struct ComponentEntry {
+  flag:    ComponentFlags,
+  storage: Box<AnyStorage>,
+}
 
-  pub entities: FlagsArray,
+/// Change to the `EntityManager` deferred via `EntityManager::defer`.
+trait Command: Send {
+  fn apply(self: Box<Self>, manager: &mut EntityManager);
+}
 
-  pub positions:  ComponentArray<Position>,
-  pub scales:     ComponentArray<Scale>,
-  pub rotations:  ComponentArray<Rotation>,
-  pub velocities: ComponentArray<Velocity>,
-  pub geometries: ComponentArray<Geometry>,
-  pub pickables:  ComponentArray<Pickable>,
-  pub cameras:    ComponentArray<Camera>,
-  pub bobs:       ComponentArray<Bob>,
+impl<F: FnOnce(&mut EntityManager) + Send> Command for F {
+  fn apply(self: Box<Self>, manager: &mut EntityManager) {
+    let command = *self;
+    command(manager)
+  }
+}
+
+pub struct EntityManager {
+  entities: Entities,
+
+  components: HashMap<TypeId, ComponentEntry>,
+  // Bit used for the next registered component type
+  next_flag_bit: u32,
+  // Applied by `apply_deferred`
+  deferred: Mutex<Vec<Box<Command>>>,
 
   pub picked_entity: Option<EntityId>,
 }
 
+impl Default for EntityManager {
+  fn default() -> Self {
+    let mut manager = EntityManager {
+      entities: Entities::default(),
+      components: HashMap::new(),
+      next_flag_bit: 15,
+      deferred: Mutex::new(Vec::new()),
+      picked_entity: None,
+    };
+
+    manager.register_with_flag::<Transform>(FLAG_TRANSFORM);
+    manager.register_with_flag::<Velocity>(FLAG_VELOCITY);
+    manager.register_with_flag::<Geometry>(FLAG_GEOMETRY);
+    manager.register_with_flag::<Pickable>(FLAG_PICKABLE);
+    manager.register_with_flag::<Camera>(FLAG_CAMERA);
+    manager.register_with_flag::<Bob>(FLAG_BOB);
+    manager.register_with_flag::<Parent>(FLAG_PARENT);
+    manager.register_with_flag::<WorldTransform>(FLAG_WORLD_TRANSFORM);
+    manager.register_with_flag::<Light>(FLAG_LIGHT);
+    manager.register_with_flag::<Shadows>(FLAG_SHADOWS);
+
+    manager
+  }
+}
+
 impl EntityManager {
-  pub fn entity_iter<'a>(flags: &'a FlagsArray, flag: ComponentFlags) -> EntityIterator<'a> {
+  pub fn entity_iter<'a>(entities: &'a Entities, flag: ComponentFlags) -> EntityIterator<'a> {
     EntityIterator {
       idx: 0,
-      entities: &flags[..],
+      entities: entities,
       flags: flag,
     }
   }
 
+  fn register_with_flag<T: Component>(&mut self, flag: ComponentFlags) {
+    let storage: StorageCell<T> = RwLock::new(ComponentArray::default());
+    self.components.insert(TypeId::of::<T>(), ComponentEntry {
+      flag: flag,
+      storage: Box::new(storage),
+    });
+  }
+
+  /// Registers `T` as a component type, allocating its storage and flag
+  /// bit. Registering a type twice returns the already allocated flag.
+  pub fn register<T: Component>(&mut self) -> ComponentFlags {
+    if let Some(entry) = self.components.get(&TypeId::of::<T>()) {
+      return entry.flag;
+    }
+
+    assert!(self.next_flag_bit < 64, "Ran out of component flags");
+    let flag = ComponentFlags::from_bit(self.next_flag_bit);
+    self.next_flag_bit += 1;
+    self.register_with_flag::<T>(flag);
+    flag
+  }
+
+  /// Returns the flag of component type `T`, if it's registered.
+  pub fn flag<T: Component>(&self) -> Option<ComponentFlags> {
+    self.components.get(&TypeId::of::<T>()).map(|entry| entry.flag)
+  }
+
+  fn storage_cell<T: Component>(&self) -> Option<&StorageCell<T>> {
+    self.components.get(&TypeId::of::<T>()).map(|entry| {
+      entry.storage.as_any().downcast_ref::<StorageCell<T>>().unwrap()
+    })
+  }
+
+  /// Borrows the storage of component type `T`. Panics if `T` isn't
+  /// registered or the storage is already mutably borrowed. Borrows
+  /// never block, so a system accessing a storage it didn't declare
+  /// panics instead of deadlocking.
+  pub fn storage<T: Component>(&self) -> ReadStorage<T> {
+    self.storage_cell::<T>()
+      .expect("Component type isn't registered")
+      .try_read()
+      .expect("Component storage is already mutably borrowed")
+  }
+
+  /// Mutably borrows the storage of component type `T`. Panics if `T`
+  /// isn't registered or the storage is already borrowed.
+  pub fn storage_mut<T: Component>(&self) -> WriteStorage<T> {
+    self.storage_cell::<T>()
+      .expect("Component type isn't registered")
+      .try_write()
+      .expect("Component storage is already borrowed")
+  }
+
+  /// Adds `component` to `entity`, registering `T` if needed. Returns
+  /// the component it replaced.
+  pub fn insert<T: Component>(&mut self, entity: EntityId, component: T) -> Option<T> {
+    self.check_alive(entity);
+    let flag = self.register::<T>();
+    self.entities[entity].insert(flag);
+    self.storage_mut::<T>().insert(entity, component)
+  }
+
+  pub fn remove<T: Component>(&mut self, entity: EntityId) -> Option<T> {
+    self.check_alive(entity);
+    match self.flag::<T>() {
+      Some(flag) => {
+        self.entities[entity].remove(flag);
+        self.storage_mut::<T>().remove(entity)
+      },
+      None => None,
+    }
+  }
+
+  pub fn has<T: Component>(&self, entity: EntityId) -> bool {
+    match self.flag::<T>() {
+      Some(flag) => self.is_alive(entity) && self.entities[entity].contains(flag),
+      None => false,
+    }
+  }
+
+  /// Iterates over all entities matching `join`, see `Join`.
+  pub fn join<J: Join>(&self, join: J) -> Query<J> {
+    Query::new(self, join)
+  }
+
+  pub fn get<T: Component>(&self, entity: EntityId) -> Option<ComponentRef<T>> {
+    if !self.has::<T>(entity) {
+      return None;
+    }
+    Some(ComponentRef::new(self.storage::<T>(), entity))
+  }
+
+  pub fn get_mut<T: Component>(&self, entity: EntityId) -> Option<ComponentRefMut<T>> {
+    if !self.has::<T>(entity) {
+      return None;
+    }
+    Some(ComponentRefMut::new(self.storage_mut::<T>(), entity))
+  }
+
+  pub fn entities(&self) -> &Entities {
+    &self.entities
+  }
+
+  /// Queues a change needing exclusive access, like creating or deleting
+  /// entities and adding or removing components. Systems only get shared
+  /// access, the scheduler applies queued changes after every stage.
+  pub fn defer<F>(&self, command: F)
+    where F: FnOnce(&mut EntityManager) + Send + 'static {
+    self.deferred.lock().unwrap().push(Box::new(command));
+  }
+
+  /// Applies all changes queued by `defer`, in the order they were
+  /// queued.
+  pub fn apply_deferred(&mut self) {
+    loop {
+      let commands: Vec<_> = self.deferred.get_mut().unwrap().drain(..).collect();
+      if commands.is_empty() {
+        break;
+      }
+      // Commands may queue further commands
+      for command in commands {
+        command.apply(self);
+      }
+    }
+  }
+
+  pub fn flags(&self, entity: EntityId) -> ComponentFlags {
+    self.entities[entity]
+  }
+
   pub fn new_entity(&mut self) -> EntityId {
-    // TODO: Return first entity which has FLAG_NONE set when we run out of IDs
-    self.highest_id += 1;
-    assert!(self.highest_id < (self.entities.0.len() as EntityId));
-    self.entities[self.highest_id] = FLAG_NONE;
-    self.highest_id
+    self.entities.create()
   }
 
-  pub fn delete_entity(&mut self, _entity: EntityId) {
-    unimplemented!();
+  pub fn is_alive(&self, entity: EntityId) -> bool {
+    self.entities.is_alive(entity)
   }
 
-  pub fn add_geometry(&mut self, entity: EntityId, g: Geometry) {
-    self.geometries[entity] = g;
-    self.entities[entity].insert(FLAG_GEOMETRY);
+  fn check_alive(&self, entity: EntityId) {
+    assert!(self.is_alive(entity), "Entity {} isn't alive", entity);
   }
 
-  pub fn set_position<P: Into<Position>>(&mut self, entity: EntityId, p: P) {
-    self.positions[entity] = p.into();
-    self.entities[entity].insert(FLAG_POSITION);
+  pub fn delete_entity(&mut self, entity: EntityId) {
+    self.entities.destroy(entity);
+
+    for entry in self.components.values_mut() {
+      entry.storage.remove_entity(entity);
+    }
+
+    // Release the picking ID
+    if self.picked_entity == Some(entity) {
+      self.picked_entity = None;
+    }
+  }
+
+  pub fn add_geometry(&mut self, entity: EntityId, g: Geometry) {
+    self.insert(entity, g);
   }
 
   pub fn add_camera(&mut self, entity: EntityId, camera: Camera) {
-    self.cameras[entity] = camera;
-    self.entities[entity].insert(FLAG_CAMERA);
+    if let Some(target) = camera.tracking {
+      assert!(self.is_alive(target), "Camera target {} isn't alive", target);
+    }
+    self.insert(entity, camera);
   }
 
-  pub fn set_scale<S: Into<Scale>>(&mut self, entity: EntityId, scale: S) {
-    self.scales[entity] = scale.into();
-    self.entities[entity].insert(FLAG_SCALE);
+  pub fn set_velocity(&mut self, entity: EntityId, velocity: Velocity) {
+    self.insert(entity, velocity);
   }
 
-  pub fn set_rotation(&mut self, entity: EntityId, rot: Rotation) {
-    self.rotations[entity] = rot;
-    self.entities[entity].insert(FLAG_ROTATION);
+  pub fn add_bob(&mut self, entity: EntityId, bob: Bob) {
+    self.insert(entity, bob);
   }
 
   pub fn set_pickable(&mut self, entity: EntityId, enable: bool) {
     if enable {
-      self.pickables[entity] = Pickable(entity);
-      self.entities[entity].insert(FLAG_PICKABLE);
+      self.insert(entity, Pickable(entity.index()));
     } else {
-      self.entities[entity].remove(FLAG_PICKABLE);
+      self.remove::<Pickable>(entity);
     }
   }
+
+  /// Maps an ID read from the picking buffer back to a live entity.
+  pub fn pickable_entity(&self, id: u32) -> Option<EntityId> {
+    self.entities.get(id).and_then(|entity| {
+      if self.entities[entity].contains(FLAG_PICKABLE) { Some(entity) } else { None }
+    })
+  }
+}
+
+macro_rules! component_accessors {
+  ( $( $t:ident: $get:ident, $has:ident, $remove:ident; )+ ) => {
+    impl EntityManager {
+      $(
+        pub fn $get(&self, entity: EntityId) -> Option<ComponentRef<$t>> {
+          self.get::<$t>(entity)
+        }
+
+        pub fn $has(&self, entity: EntityId) -> bool {
+          self.has::<$t>(entity)
+        }
+
+        pub fn $remove(&mut self, entity: EntityId) -> Option<$t> {
+          self.remove::<$t>(entity)
+        }
+      )+
+    }
+  };
+}
+
+component_accessors! {
+  Velocity: velocity, has_velocity, remove_velocity;
+  Geometry: geometry, has_geometry, remove_geometry;
+  Pickable: pickable, has_pickable, remove_pickable;
+  Camera:   camera,   has_camera,   remove_camera;
+  Bob:      bob,      has_bob,      remove_bob;
+  Light:    light,    has_light,    remove_light;
+  Shadows:  shadows,  has_shadows,  remove_shadows;
 }
 
 pub struct PickingSystem {
@@ -344,7 +663,7 @@ impl PickingSystem {
   }
 
 
-  pub fn read_picking_buffer(&mut self) -> Option<EntityId> {
+  pub fn read_picking_buffer(&mut self) -> Option<u32> {
     // Copy the picking_vbo into main memory and read its value
     self.pbo.read().ok().and_then(|px| {
       if px[0] > 0 { Some(px[0]) } else { None }
@@ -369,44 +688,60 @@ impl PickingSystem {
 
 pub struct WorldUniforms {
   pub projection_matrix: na::Matrix4<f32>,
-  pub light_position:    na::Vector3<f32>,
   pub camera_matrix:     na::Matrix4<f32>,
   pub camera_position:   na::Vector3<f32>,
 }
 
 #[derive(Default)]
 pub struct VelocitySystem;
-impl VelocitySystem {
-  pub fn run(manager: &mut EntityManager, delta: Millis) {
-    for entity in EntityManager::entity_iter(&manager.entities, FLAG_VELOCITY) {
-      let velocity = manager.velocities[entity];
+impl System for VelocitySystem {
+  fn access(&self) -> Option<Access> {
+    Some(Access::new()
+         .read::<Velocity>()
+         .write::<Transform>())
+  }
+
+  fn run(&mut self, manager: &EntityManager, delta: Millis) {
+    let velocities     = manager.storage::<Velocity>();
+    let mut transforms = manager.storage_mut::<Transform>();
+
+    for (_, (velocity, transform)) in manager.join((&*velocities, &mut *transforms)) {
       let delta = delta.as_seconds();
-      // Update component.position
-      manager.positions[entity].0 += velocity.linear * delta;
+      // Update translation
+      transform.translate(velocity.linear * delta);
 
-      // Update component.rotation
-      if manager.entities[entity].contains(FLAG_ROTATION) {
+      // Update rotation
+      if let Some(axis) = velocity.angular.0.axis() {
         let angle = velocity.angular.0.angle()*delta;
-        let axis  = velocity.angular.0.axis().unwrap();
-
-        manager.rotations[entity].0 *= UnitQuaternion::from_axis_angle(&axis, angle)
-      } else if velocity.angular.0.angle() != 0.0 {
-        // If rotation isn't enabled log a warning
-        // TODO: Rate-limit
-        println!("Entity {}: Got angular rotation but FLAG_ROTATION isn't set", entity);
+        transform.rotate(UnitQuaternion::from_axis_angle(&axis, angle));
       }
     }
   }
 }
 
+#[derive(Default)]
 pub struct CameraSystem;
-impl CameraSystem {
-  pub fn run(manager: &mut EntityManager, _delta: Millis) {
-    for entity in EntityManager::entity_iter(&manager.entities, FLAG_CAMERA) {
-      let ref mut camera = manager.cameras[entity];
+impl System for CameraSystem {
+  fn access(&self) -> Option<Access> {
+    Some(Access::new()
+         .write::<Camera>()
+         .read::<Transform>()
+         .read::<WorldTransform>())
+  }
+
+  fn run(&mut self, manager: &EntityManager, _delta: Millis) {
+    let mut cameras = manager.storage_mut::<Camera>();
 
+    for (entity, camera) in manager.join(&mut *cameras) {
       if let Some(target) = camera.tracking {
-        camera.target = manager.positions[target].0;
+        if manager.is_alive(target) {
+          if let Some(position) = manager.world_position(target) {
+            camera.target = position;
+          }
+        } else {
+          println!("Entity {}: Tracked entity {} is gone", entity, target);
+          camera.tracking = None;
+        }
       }
     }
   }
@@ -435,23 +770,128 @@ impl Bob {
   }
 }
 
+#[derive(Default)]
 pub struct BobSystem;
-impl BobSystem {
-  pub fn run(manager: &mut EntityManager, delta: Millis) {
-    for entity in EntityManager::entity_iter(&manager.entities, FLAG_BOB) {
-      let ref mut bob = manager.bobs[entity];
+impl System for BobSystem {
+  fn access(&self) -> Option<Access> {
+    Some(Access::new()
+         .write::<Bob>()
+         .write::<Transform>())
+  }
 
+  fn run(&mut self, manager: &EntityManager, delta: Millis) {
+    let mut bobs       = manager.storage_mut::<Bob>();
+    let mut transforms = manager.storage_mut::<Transform>();
+
+    for (_, (bob, transform)) in manager.join((&mut *bobs, &mut *transforms)) {
       // Update new Bob state
-      bob.state += delta;
-      if bob.state.as_millis() >= bob.period.as_millis() {
-        bob.state -= bob.period;
-      }
+      bob.state = (bob.state + delta) % bob.period;
       // Calculate current position in Sine curve
-      let sine = ((bob.state.as_millis() / bob.period.as_millis()) * 2.0 * consts::PI).sin();
+      let sine = ((bob.state / bob.period) * 2.0 * consts::PI).sin();
       // Calculate position-delta (direction*sine scaled by delta-t)
-      let td = delta.as_millis() / bob.period.as_millis();
+      let td = delta / bob.period;
       let pd = sine * td * bob.direction;
-      manager.positions[entity].0 += pd;
+      transform.translate(pd);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Debug, PartialEq)]
+  struct Health(u32);
+
+  #[derive(Debug, PartialEq)]
+  struct Mana(u32);
+
+  #[test]
+  fn registered_components_get_distinct_flags() {
+    let mut manager = EntityManager::default();
+    let health = manager.register::<Health>();
+    let mana   = manager.register::<Mana>();
+
+    assert!(!health.is_empty());
+    assert!(!mana.is_empty());
+    assert!(!health.intersects(mana));
+    assert!(!health.intersects(FLAG_NONE | FLAG_TRANSFORM | FLAG_SHADOWS));
+    assert_eq!(manager.register::<Health>(), health);
+  }
+
+  #[test]
+  fn iterating_without_entities() {
+    let mut manager = EntityManager::default();
+    assert_eq!(EntityManager::entity_iter(&manager.entities, FLAG_NONE).count(), 0);
+
+    let entity = manager.new_entity();
+    let found: Vec<_> = EntityManager::entity_iter(&manager.entities, FLAG_NONE).collect();
+    assert_eq!(found, vec![entity]);
+
+    manager.delete_entity(entity);
+    assert_eq!(EntityManager::entity_iter(&manager.entities, FLAG_NONE).count(), 0);
+  }
+
+  #[test]
+  fn custom_components() {
+    let mut manager = EntityManager::default();
+    let a = manager.new_entity();
+    let b = manager.new_entity();
+
+    manager.insert(a, Health(10));
+    manager.insert(b, Mana(5));
+
+    assert!(manager.has::<Health>(a));
+    assert!(!manager.has::<Health>(b));
+    assert!(!manager.has::<Mana>(a));
+    assert_eq!(*manager.get::<Health>(a).unwrap(), Health(10));
+    assert!(manager.get::<Health>(b).is_none());
+
+    {
+      let healths = manager.storage::<Health>();
+      let found: Vec<_> = manager.join(&*healths).map(|(entity, _)| entity).collect();
+      assert_eq!(found, vec![a]);
+    }
+
+    {
+      let healths = manager.storage::<Health>();
+      let found: Vec<_> = manager.join(Maybe(&*healths))
+        .without::<Mana>()
+        .map(|(entity, _)| entity)
+        .collect();
+      assert_eq!(found, vec![a]);
+    }
+
+    assert_eq!(manager.remove::<Health>(a), Some(Health(10)));
+    assert!(!manager.has::<Health>(a));
+    assert!(manager.get::<Health>(a).is_none());
+    assert_eq!(manager.remove::<Health>(a), None);
+
+    let healths = manager.storage::<Health>();
+    assert_eq!(manager.join(&*healths).count(), 0);
+  }
+
+  #[test]
+  fn joins_only_yield_entities_having_all_components() {
+    let mut manager = EntityManager::default();
+    let entities: Vec<_> = (0..10).map(|_| manager.new_entity()).collect();
+    for (i, &entity) in entities.iter().enumerate() {
+      manager.insert(entity, Health(i as u32));
+      if i % 3 == 0 {
+        manager.insert(entity, Mana(i as u32));
+      }
     }
+    manager.delete_entity(entities[3]);
+
+    let healths = manager.storage::<Health>();
+    let manas   = manager.storage::<Mana>();
+    let mut found: Vec<_> = manager.join((&*healths, &*manas))
+      .map(|(entity, (health, mana))| {
+        assert_eq!(health.0, mana.0);
+        entity
+      })
+      .collect();
+    found.sort_by_key(|entity| entity.index());
+    assert_eq!(found, vec![entities[0], entities[6], entities[9]]);
   }
 }
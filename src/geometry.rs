@@ -56,6 +56,17 @@ pub struct Normal { normal: [f32; 3] }
 implement_vertex!(Normal, normal);
 implement_from!(Normal, normal);
 
+/// Tangent for normal mapping, `w` is the sign of the bitangent.
+#[derive(Debug, Clone, Copy)]
+pub struct Tangent { tangent: [f32; 4] }
+implement_vertex!(Tangent, tangent);
+
+impl From<na::Vector4<f32>> for Tangent {
+  fn from(v: na::Vector4<f32>) -> Self {
+    Tangent { tangent: [v.x, v.y, v.z, v.w] }
+  }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Material {
   pub ambient:   [f32; 4], // Fourth element is needed for padding
@@ -80,3 +91,34 @@ implement_uniform_block!(Material,
                          diffuse,
                          specular,
                          shininess);
+
+/// Metallic-roughness material, used by the `pbr` program. Textures are
+/// multiplied with the corresponding factors.
+#[derive(Debug, Clone, Copy)]
+pub struct PbrMaterial {
+  pub base_color: [f32; 4],
+  pub emissive:   [f32; 4], // Fourth element is needed for padding
+  pub metallic:   f32,
+  pub roughness:  f32,
+  // Strength of the occlusion texture
+  pub occlusion:  f32,
+}
+
+impl Default for PbrMaterial {
+  fn default() -> Self {
+    PbrMaterial {
+      base_color: [1.0; 4],
+      emissive:   [0.0; 4],
+      metallic:   0.0,
+      roughness:  0.5,
+      occlusion:  1.0,
+    }
+  }
+}
+
+implement_uniform_block!(PbrMaterial,
+                         base_color,
+                         emissive,
+                         metallic,
+                         roughness,
+                         occlusion);
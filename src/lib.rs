@@ -1,58 +1,64 @@
 #[macro_use] extern crate glium;
 pub extern crate nalgebra;
 extern crate alga;
+extern crate rayon;
 
 #[macro_use] extern crate newtype_derive;
 #[macro_use] extern crate custom_derive;
 
-#[macro_use] extern crate bitflags;
-
 use std::fmt::Debug;
 use std::cmp::PartialEq;
 
 pub use nalgebra as na;
 pub use nalgebra::{Vector3, Vector4, UnitQuaternion, Matrix3, Matrix4};
-use std::time::Duration;
 
 mod geometry;
 pub use geometry::*;
 
+mod normals;
+pub use normals::*;
+
+mod tangents;
+pub use tangents::*;
+
+mod texture;
+pub use texture::*;
+
 mod resources;
 pub use resources::*;
 
+mod storage;
+pub use storage::*;
+
 mod components;
 pub use components::*;
 
+mod query;
+pub use query::*;
+
+mod transform;
+pub use transform::*;
+
+mod light;
+pub use light::*;
+
+mod time;
+pub use time::*;
+
+mod scheduler;
+pub use scheduler::*;
+
+mod shadow_system;
+pub use shadow_system::*;
+
 mod render_system;
 pub use render_system::*;
 
 mod world;
 pub use world::*;
 
-custom_derive! {
-  #[derive(Debug, Copy, Clone,
-           NewtypeAdd, NewtypeSub,
-           NewtypeAddAssign, NewtypeSubAssign)]
-  pub struct Millis(pub f32);
-}
-
-impl Millis {
-  pub fn as_seconds(&self) -> f32 {
-    self.0 / 1000.0
-  }
-
-  pub fn as_millis(&self) -> f32 {
-    self.0
-  }
-}
-
-impl From<Duration> for Millis {
-  fn from(dur: Duration) -> Self {
-    Millis(dur.as_secs() as f32 / 1000.0
-           +
-           dur.subsec_nanos() as f32 / 1000000.0)
-  }
-}
+mod app;
+pub use app::*;
 
 pub fn matrix3_from_matrix4<T>(m: &Matrix4<T>) -> Matrix3<T>
   where T: Copy + PartialEq + Debug + 'static {
@@ -61,6 +67,14 @@ pub fn matrix3_from_matrix4<T>(m: &Matrix4<T>) -> Matrix3<T>
                m[(0,2)], m[(1,2)], m[(2,2)])
 }
 
+/// Inverse-transpose of the upper 3x3 part of `m`, used to transform
+/// normals correctly under non-uniform scaling. Singular matrices (e.g.
+/// a zero scale) yield the identity.
+pub fn normal_matrix(m: &Matrix4<f32>) -> Matrix3<f32> {
+  // `matrix3_from_matrix4` already transposes
+  matrix3_from_matrix4(m).try_inverse().unwrap_or(Matrix3::identity())
+}
+
 pub fn quat_rotate<T>(angle: T, axis: na::Unit<na::Vector3<T>>) -> na::UnitQuaternion<T>
   where T: Copy + alga::general::Real {
   na::UnitQuaternion::from_axis_angle(&axis, angle)
@@ -102,3 +116,47 @@ impl<S: Copy + Debug + PartialEq + 'static> AsUniform<[S; 3]> for Vector3<S>  {
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn model_matrix(scale: Vector3<f32>) -> Matrix4<f32> {
+    let axis = na::Unit::new_normalize(Vector3::new(1.0, 2.0, 0.5));
+    let rotation = quat_rotate(0.7, axis).to_homogeneous();
+    let scale = Matrix4::new(scale.x, 0.0,     0.0,     0.0,
+                             0.0,     scale.y, 0.0,     0.0,
+                             0.0,     0.0,     scale.z, 0.0,
+                             0.0,     0.0,     0.0,     1.0);
+    rotation * scale
+  }
+
+  // Upper 3x3 part of `m`, as applied to directions
+  fn linear_part(m: &Matrix4<f32>) -> Matrix3<f32> {
+    matrix3_from_matrix4(m).transpose()
+  }
+
+  #[test]
+  fn normal_matrix_keeps_normals_perpendicular() {
+    let m = model_matrix(Vector3::new(1.0, 0.05, 3.0));
+    let normal  = Vector3::new(1.0, 1.0, 1.0).normalize();
+    let tangent = Vector3::new(1.0, -1.0, 0.0).normalize();
+
+    let tangent = (linear_part(&m) * tangent).normalize();
+    let normal  = (normal_matrix(&m) * normal).normalize();
+    assert!(normal.dot(&tangent).abs() < 1e-5);
+
+    // Transforming the normal like a position doesn't
+    let naive = (linear_part(&m) * Vector3::new(1.0, 1.0, 1.0)).normalize();
+    assert!(naive.dot(&tangent).abs() > 0.1);
+  }
+
+  #[test]
+  fn normal_matrix_of_singular_matrix() {
+    let m = Matrix4::new(1.0, 0.0, 0.0, 2.0,
+                         0.0, 0.0, 0.0, 0.0,
+                         0.0, 0.0, 1.0, 0.0,
+                         0.0, 0.0, 0.0, 1.0);
+    assert_eq!(normal_matrix(&m), Matrix3::identity());
+  }
+}
@@ -0,0 +1,258 @@
+use glium as gl;
+use nalgebra as na;
+use nalgebra::Vector3;
+
+use std::f32::consts;
+
+use super::components::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+  /// Shines in all directions from the entity's position.
+  Point,
+  /// Shines along the entity's forward axis (-Z) from infinitely far
+  /// away, e.g. the sun.
+  Directional,
+  /// Cone along the entity's forward axis (-Z). Angles are in radians
+  /// and measured from the axis, with a smooth falloff between them.
+  Spot { inner_angle: f32, outer_angle: f32 },
+}
+
+/// Distance attenuation `1 / (constant + linear*d + quadratic*d²)`.
+/// Ignored by directional lights.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+  pub constant:  f32,
+  pub linear:    f32,
+  pub quadratic: f32,
+}
+
+impl Default for Attenuation {
+  fn default() -> Self {
+    Attenuation::from_range(50.0)
+  }
+}
+
+impl Attenuation {
+  /// No attenuation at all.
+  pub fn none() -> Self {
+    Attenuation {
+      constant:  1.0,
+      linear:    0.0,
+      quadratic: 0.0,
+    }
+  }
+
+  /// Attenuation falling off to roughly 1% at `range`.
+  pub fn from_range(range: f32) -> Self {
+    assert!(range > 0.0);
+    Attenuation {
+      constant:  1.0,
+      linear:    4.5 / range,
+      quadratic: 75.0 / (range * range),
+    }
+  }
+}
+
+/// Shadow map parameters of a shadow casting light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowSettings {
+  /// Depth offset against shadow acne, scaled up for surfaces at
+  /// grazing angles to the light.
+  pub bias:   f32,
+  /// Half the width and height of the area covered by a directional
+  /// light's shadow map, centered on the light's position.
+  pub extent: f32,
+  /// Depth range of spot and point light shadow maps. Directional
+  /// lights cover twice the extent in depth.
+  pub near:   f32,
+  pub far:    f32,
+  /// Size of each cube map face of a point light's shadow map.
+  /// Directional and spot lights share the `ShadowSystem`'s map size.
+  pub resolution: u32,
+}
+
+impl Default for ShadowSettings {
+  fn default() -> Self {
+    ShadowSettings {
+      bias:   0.005,
+      extent: 10.0,
+      near:   0.1,
+      far:    50.0,
+      resolution: 1024,
+    }
+  }
+}
+
+/// Light source placed (and, for directional and spot lights, oriented)
+/// by the entity's world transform.
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+  pub kind:        LightKind,
+  pub color:       Vector3<f32>,
+  pub intensity:   f32,
+  pub attenuation: Attenuation,
+  /// `None` for lights not casting shadows.
+  pub shadows:     Option<ShadowSettings>,
+}
+
+impl Default for Light {
+  fn default() -> Self {
+    Light::point(Vector3::new(1.0, 1.0, 1.0), 1.0)
+  }
+}
+
+impl Light {
+  pub fn point(color: Vector3<f32>, intensity: f32) -> Self {
+    Light {
+      kind:        LightKind::Point,
+      color:       color,
+      intensity:   intensity,
+      attenuation: Attenuation::default(),
+      shadows:     None,
+    }
+  }
+
+  pub fn directional(color: Vector3<f32>, intensity: f32) -> Self {
+    Light {
+      kind:        LightKind::Directional,
+      color:       color,
+      intensity:   intensity,
+      attenuation: Attenuation::none(),
+      shadows:     None,
+    }
+  }
+
+  pub fn spot(color: Vector3<f32>, intensity: f32, inner_angle: f32, outer_angle: f32) -> Self {
+    assert!(inner_angle <= outer_angle, "Inner cone angle must not exceed the outer one");
+    Light {
+      kind:        LightKind::Spot { inner_angle: inner_angle, outer_angle: outer_angle },
+      color:       color,
+      intensity:   intensity,
+      attenuation: Attenuation::default(),
+      shadows:     None,
+    }
+  }
+
+  pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+    self.attenuation = attenuation;
+    self
+  }
+
+  pub fn with_shadows(mut self, settings: ShadowSettings) -> Self {
+    self.shadows = Some(settings);
+    self
+  }
+
+  /// Projection * view matrix used to render the shadow map of a
+  /// directional or spot light placed at `world`. `None` if the light
+  /// doesn't cast shadows or is a point light, see `cube_shadow_matrices`.
+  pub fn shadow_matrix(&self, world: &na::Matrix4<f32>) -> Option<na::Matrix4<f32>> {
+    let settings = match self.shadows {
+      Some(settings) => settings,
+      None => return None,
+    };
+
+    let position  = Vector3::new(world[(0,3)], world[(1,3)], world[(2,3)]);
+    let direction = forward(world);
+    // Avoid a degenerate view matrix for lights facing straight up/down
+    let up = if direction.y.abs() > 0.99 {
+      Vector3::new(1.0, 0.0, 0.0)
+    } else {
+      Vector3::new(0.0, 1.0, 0.0)
+    };
+
+    match self.kind {
+      LightKind::Directional => {
+        // Pull the eye back so the light's position ends up centered
+        let e = settings.extent;
+        let eye = position - direction * e;
+        let view = look_at(eye, direction, up);
+        let projection = na::Orthographic3::new(-e, e, -e, e, settings.near, 2.0 * e).unwrap();
+        Some(projection * view)
+      },
+      LightKind::Spot { outer_angle, .. } => {
+        let view = look_at(position, direction, up);
+        let projection = na::Perspective3::new(1.0, 2.0 * outer_angle, settings.near, settings.far).unwrap();
+        Some(projection * view)
+      },
+      LightKind::Point => None,
+    }
+  }
+}
+
+/// Cube map faces in the order of `cube_shadow_matrices`.
+pub const CUBE_FACES: [gl::texture::CubeLayer; 6] = [
+  gl::texture::CubeLayer::PositiveX,
+  gl::texture::CubeLayer::NegativeX,
+  gl::texture::CubeLayer::PositiveY,
+  gl::texture::CubeLayer::NegativeY,
+  gl::texture::CubeLayer::PositiveZ,
+  gl::texture::CubeLayer::NegativeZ,
+];
+
+/// Projection * view matrices for rendering the six faces (see
+/// `CUBE_FACES`) of a point light's shadow cube map.
+pub fn cube_shadow_matrices(position: Vector3<f32>, settings: &ShadowSettings) -> [na::Matrix4<f32>; 6] {
+  // Directions and up vectors following GL's cube map conventions
+  let faces = [
+    (Vector3::new( 1.0,  0.0,  0.0), Vector3::new(0.0, -1.0,  0.0)),
+    (Vector3::new(-1.0,  0.0,  0.0), Vector3::new(0.0, -1.0,  0.0)),
+    (Vector3::new( 0.0,  1.0,  0.0), Vector3::new(0.0,  0.0,  1.0)),
+    (Vector3::new( 0.0, -1.0,  0.0), Vector3::new(0.0,  0.0, -1.0)),
+    (Vector3::new( 0.0,  0.0,  1.0), Vector3::new(0.0, -1.0,  0.0)),
+    (Vector3::new( 0.0,  0.0, -1.0), Vector3::new(0.0, -1.0,  0.0)),
+  ];
+
+  let projection = na::Perspective3::new(1.0, consts::FRAC_PI_2, settings.near, settings.far).unwrap();
+  let mut matrices = [na::Matrix4::identity(); 6];
+  for (m, &(direction, up)) in matrices.iter_mut().zip(faces.iter()) {
+    *m = projection * look_at(position, direction, up);
+  }
+  matrices
+}
+
+fn look_at(eye: Vector3<f32>, direction: Vector3<f32>, up: Vector3<f32>) -> na::Matrix4<f32> {
+  na::Isometry3::look_at_rh(&na::Point3::from_coordinates(eye),
+                            &na::Point3::from_coordinates(eye + direction),
+                            &up)
+    .to_homogeneous()
+}
+
+/// Per-entity shadow flags. Entities without this component both cast
+/// and receive shadows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadows {
+  pub cast:    bool,
+  pub receive: bool,
+}
+
+impl Default for Shadows {
+  fn default() -> Self {
+    Shadows {
+      cast:    true,
+      receive: true,
+    }
+  }
+}
+
+impl EntityManager {
+  pub fn add_light(&mut self, entity: EntityId, light: Light) {
+    self.insert(entity, light);
+  }
+
+  pub fn set_shadows(&mut self, entity: EntityId, cast: bool, receive: bool) {
+    self.insert(entity, Shadows { cast: cast, receive: receive });
+  }
+
+  /// Direction `entity` faces in world space (its -Z axis).
+  pub fn world_direction(&self, entity: EntityId) -> Option<Vector3<f32>> {
+    self.world_transform(entity).map(|world| forward(&world.matrix()))
+  }
+}
+
+/// The -Z axis of `m`.
+pub fn forward(m: &na::Matrix4<f32>) -> Vector3<f32> {
+  let z = Vector3::new(m[(0,2)], m[(1,2)], m[(2,2)]);
+  -z.normalize()
+}
@@ -4,108 +4,122 @@ extern crate kaffeesahne;
 use std::f32::consts;
 use glium as gl;
 use kaffeesahne::*;
-use std::time::{Duration,Instant};
+
+fn load_resources(display: &gl::Display, resources: &mut ResourceManager) -> Result<(), ResourceError> {
+  resources.load_obj(display, "terrain", "terrain.obj")?;
+  resources.load_obj(display, "light", "light.obj")?;
+  resources.load_obj(display, "cube", "toruscube.obj")?;
+  resources.make_axis_object(display, "axis")?;
+
+  resources.compile_shader(display,
+                           "basic",
+                           "src/shaders/basic.vertex.glsl",
+                           "src/shaders/basic.fragment.glsl")?;
+  resources.compile_shader(display,
+                           "pbr",
+                           "src/shaders/basic.vertex.glsl",
+                           "src/shaders/pbr.fragment.glsl")?;
+  // TODO: Move to RenderSystem
+  resources.compile_shader(display,
+                           "picking",
+                           "src/shaders/picking.vertex.glsl",
+                           "src/shaders/picking.fragment.glsl")?;
+  // TODO: Move to RenderSystem
+  resources.compile_shader(display,
+                           "shadow",
+                           "src/shaders/shadow.vertex.glsl",
+                           "src/shaders/shadow.fragment.glsl")?;
+  resources.compile_shader(display,
+                           "shadow_cube",
+                           "src/shaders/shadow_cube.vertex.glsl",
+                           "src/shaders/shadow_cube.fragment.glsl")?;
+  resources.compile_shader(display,
+                           "shadow_debug",
+                           "src/shaders/shadow_debug.vertex.glsl",
+                           "src/shaders/shadow_debug.fragment.glsl")?;
+  // TODO: Move to RenderSystem
+  resources.compile_shader(display,
+                           "axis",
+                           "src/shaders/axis.vertex.glsl",
+                           "src/shaders/axis.fragment.glsl")?;
+
+  Ok(())
+}
 
 fn main() {
-  use glium::DisplayBuild;
-  let display = gl::glutin::WindowBuilder::new()
-    .with_depth_buffer(24)
+  let window = gl::glutin::WindowBuilder::new()
     // .with_multisampling(8)
-    .build_glium().unwrap();
+    .with_depth_buffer(24);
+  let mut app = App::new(window);
 
-  let mut world = World::new(&display);
+  app.on_setup(|display, world| {
+    if let Err(e) = load_resources(display, &mut world.resources) {
+      panic!("Can't load resources: {}", e);
+    }
 
-  {
-    let mut resources = &mut world.resources;
-    resources.load_obj(&display, "terrain", "terrain.obj");
-    resources.load_obj(&display, "light", "light.obj");
-    resources.load_obj(&display, "cube", "toruscube.obj");
-    resources.make_axis_object(&display, "axis");
+    let terrain = world.entities.new_entity();
+    {
+      world.entities.set_position(terrain, Vector3::new(0.0, 0.0, 0.0));
+      world.entities.add_geometry(terrain, Geometry {
+        geometry: "terrain",
+        program:  "basic",
+      });
+      world.entities.set_pickable(terrain, true);
+    }
 
-    resources.compile_shader(&display,
-                             "basic",
-                             "src/shaders/basic.vertex.glsl",
-                             "src/shaders/basic.fragment.glsl");
-    // TODO: Move to RenderSystem
-    resources.compile_shader(&display,
-                             "picking",
-                             "src/shaders/picking.vertex.glsl",
-                             "src/shaders/picking.fragment.glsl");
-    // TODO: Move to RenderSystem
-    resources.compile_shader(&display,
-                             "axis",
-                             "src/shaders/axis.vertex.glsl",
-                             "src/shaders/axis.fragment.glsl");
-  }
+    {
+      let light = world.entities.new_entity();
+      world.entities.set_position(light, Vector3::new(1.0, 1.0, 0.0));
+      world.entities.add_light(light, Light::point(Vector3::new(1.0, 1.0, 1.0), 1.0)
+                               .with_shadows(ShadowSettings {
+                                 resolution: 512,
+                                 bias: 0.002,
+                                 ..Default::default()
+                               }));
+      // The marker mesh would block the light otherwise
+      world.entities.set_shadows(light, false, false);
+      world.entities.set_pickable(light, true);
+      world.entities.add_geometry(light, Geometry {
+        geometry: "light",
+        program:  "basic",
+      });
+      world.entities.set_scale(light, Scale(na::Vector3::new(0.05, 0.05, 0.05)));
+    }
 
-  let terrain = world.entities.new_entity();
-  {
-    world.entities.set_position(terrain, Vector3::new(0.0, 0.0, 0.0));
-    world.entities.add_geometry(terrain, Geometry {
-      geometry: "terrain",
-      program:  "basic",
-    });
-    world.entities.set_pickable(terrain, true);
-  }
+    // Sun casting shadows onto the terrain
+    {
+      let sun = world.entities.new_entity();
+      world.entities.set_position(sun, Vector3::new(0.0, 2.0, 0.0));
+      world.entities.set_rotation(sun, Rotation(quat_rotate(-consts::PI/3.0, na::Unit::new_normalize(Vector3::new(1.0, 0.0, 0.0)))));
+      world.entities.add_light(sun, Light::directional(Vector3::new(1.0, 0.95, 0.8), 0.6)
+                               .with_shadows(ShadowSettings::default()));
+    }
 
-  world.light = na::Vector3::new(1.0, 1.0, 0.0);
-  {
-    let light = world.entities.new_entity();
-    let position = Position(world.light);
-    world.entities.set_position(light, position);
-    world.entities.set_pickable(light, true);
-    world.entities.add_geometry(light, Geometry {
-      geometry: "light",
-      program:  "basic",
-    });
-    world.entities.set_scale(light, Scale(na::Vector3::new(0.05, 0.05, 0.05)));
-  }
+    let cube = world.entities.new_entity();
+    {
+      world.entities.set_position(cube, Vector3::new(0.0, 0.75, 0.0));
+      world.entities.add_geometry(cube, Geometry {
+        geometry: "cube",
+        program:  "pbr",
+      });
+      world.entities.set_pickable(cube, true);
+
+      world.entities.set_rotation(cube, Rotation::default());
+      world.entities.set_velocity(cube, Velocity {
+        linear: na::zero(),
+        angular: Rotation(quat_rotate(2.0*consts::PI/8.0, na::Unit::new_normalize(Vector3::new(0.0, 1.0, 0.0)))),
+      });
+
+      world.entities.add_bob(cube, Bob::new(Millis(1000.0), Vector3::new(0.0, 0.5, 0.0)));
+    }
 
-  let cube = world.entities.new_entity();
-  {
-    world.entities.set_position(cube, Vector3::new(0.0, 0.75, 0.0));
-    world.entities.add_geometry(cube, Geometry {
-      geometry: "cube",
-      program:  "basic",
+    let camera = world.entities.new_entity();
+    world.entities.add_camera(camera, Camera {
+      target: Vector3::new(0.0, 0.0, 0.0),
+      tracking: Some(cube),
     });
-    world.entities.set_pickable(cube, true);
-
-    world.entities.entities[cube].insert(FLAG_VELOCITY);
-    world.entities.entities[cube].insert(FLAG_ROTATION);
-    world.entities.velocities[cube] = Velocity {
-      linear: na::zero(),
-      angular: Rotation(quat_rotate(2.0*consts::PI/8.0, na::Unit::new_normalize(Vector3::new(0.0, 1.0, 0.0)))),
-    };
-
-    world.entities.entities[cube].insert(FLAG_BOB);
-    world.entities.bobs[cube] = Bob::new(Millis(1000.0), Vector3::new(0.0, 0.5, 0.0));
-  }
-
-  let camera = world.entities.new_entity();
-  world.entities.add_camera(camera, Camera {
-    target: Vector3::new(0.0, 0.0, 0.0),
-    tracking: Some(cube),
+    world.entities.set_position(camera, Position(Vector3::new(0.5, 2.0, -3.0)));
   });
-  world.entities.set_position(camera, Position(Vector3::new(0.5, 2.0, -3.0)));
-
-  let ms_per_update = Duration::new(0, 1000000000/60);
-  let mut previous = Instant::now();
-  let mut lag = Duration::new(0, 00);
-
-  loop {
-    let now = Instant::now();
-    lag += now - previous;
-    previous = now;
-
-    while lag >= ms_per_update {
-      world.update(ms_per_update.into());
-      lag -= ms_per_update;
-    }
-
-    world.handle_events(display.poll_events());
 
-    let mut target = display.draw();
-    world.draw(&mut target);
-    target.finish().unwrap();
-  }
+  app.run();
 }
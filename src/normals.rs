@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use nalgebra as na;
+use nalgebra::Vector3;
+
+/// How face normals contribute to a smooth vertex normal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalWeighting {
+  /// Larger faces contribute more.
+  Area,
+  /// Faces contribute by their angle at the vertex, which doesn't
+  /// depend on how the surface is triangulated.
+  Angle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalMode {
+  /// Averages the normals of all faces sharing a position. With a
+  /// `crease_angle` (in radians), faces meeting at a sharper angle keep
+  /// a hard edge by splitting their vertices.
+  Smooth { weighting: NormalWeighting, crease_angle: Option<f32> },
+  /// Every face gets its own vertices with the face normal.
+  Flat,
+}
+
+impl Default for NormalMode {
+  fn default() -> Self {
+    NormalMode::Smooth {
+      weighting:    NormalWeighting::Angle,
+      crease_angle: Some(60f32.to_radians()),
+    }
+  }
+}
+
+/// Generates normals for a triangle mesh. Vertices are split as needed
+/// for flat shading and hard edges, so `positions`, `uvs` and `indices`
+/// get updated.
+pub fn generate_normals(positions: &mut Vec<Vector3<f32>>,
+                        uvs: &mut Vec<[f32; 2]>,
+                        indices: &mut Vec<u32>,
+                        mode: NormalMode) -> Vec<Vector3<f32>> {
+  assert_eq!(positions.len(), uvs.len());
+  assert!(indices.len() % 3 == 0);
+
+  match mode {
+    NormalMode::Flat => flat_normals(positions, uvs, indices),
+    NormalMode::Smooth { weighting, crease_angle } =>
+      smooth_normals(positions, uvs, indices, weighting, crease_angle),
+  }
+}
+
+/// Unnormalized face normal, its length is twice the triangle's area.
+fn face_normal(positions: &[Vector3<f32>], tri: &[u32]) -> Vector3<f32> {
+  let v1 = positions[tri[0] as usize];
+  let v2 = positions[tri[1] as usize];
+  let v3 = positions[tri[2] as usize];
+  (v2-v1).cross(&(v3-v1))
+}
+
+fn normalize_or_up(v: Vector3<f32>) -> Vector3<f32> {
+  if v.norm() > 1e-12 { v.normalize() } else { Vector3::new(0.0, 1.0, 0.0) }
+}
+
+fn flat_normals(positions: &mut Vec<Vector3<f32>>,
+                uvs: &mut Vec<[f32; 2]>,
+                indices: &mut Vec<u32>) -> Vec<Vector3<f32>> {
+  let mut new_positions = Vec::with_capacity(indices.len());
+  let mut new_uvs       = Vec::with_capacity(indices.len());
+  let mut normals       = Vec::with_capacity(indices.len());
+
+  for tri in indices.chunks(3) {
+    let normal = normalize_or_up(face_normal(positions, tri));
+    for &idx in tri {
+      new_positions.push(positions[idx as usize]);
+      new_uvs.push(uvs[idx as usize]);
+      normals.push(normal);
+    }
+  }
+
+  *indices   = (0..new_positions.len() as u32).collect();
+  *positions = new_positions;
+  *uvs       = new_uvs;
+  normals
+}
+
+// Positions are compared bitwise, so vertices duplicated for UV seams
+// still get smoothed together
+fn position_key(v: &Vector3<f32>) -> [u32; 3] {
+  [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]
+}
+
+fn smooth_normals(positions: &mut Vec<Vector3<f32>>,
+                  uvs: &mut Vec<[f32; 2]>,
+                  indices: &mut Vec<u32>,
+                  weighting: NormalWeighting,
+                  crease_angle: Option<f32>) -> Vec<Vector3<f32>> {
+  let face_count = indices.len() / 3;
+
+  let mut face_normals = Vec::with_capacity(face_count);
+  // Weight of every corner, indexed like `indices`
+  let mut weights = Vec::with_capacity(indices.len());
+  for tri in indices.chunks(3) {
+    let normal = face_normal(positions, tri);
+    face_normals.push(normalize_or_up(normal));
+
+    for corner in 0..3 {
+      let weight = match weighting {
+        NormalWeighting::Area => normal.norm(),
+        // Degenerate faces have no meaningful normal
+        NormalWeighting::Angle if normal.norm() < 1e-12 => 0.0,
+        NormalWeighting::Angle => {
+          let v  = positions[tri[corner] as usize];
+          let a  = positions[tri[(corner + 1) % 3] as usize] - v;
+          let b  = positions[tri[(corner + 2) % 3] as usize] - v;
+          let len = a.norm() * b.norm();
+          if len > 1e-12 { (a.dot(&b) / len).max(-1.0).min(1.0).acos() } else { 0.0 }
+        },
+      };
+      weights.push(weight);
+    }
+  }
+
+  // All corners at each position
+  let mut corners: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+  for (corner, &idx) in indices.iter().enumerate() {
+    corners.entry(position_key(&positions[idx as usize]))
+      .or_insert_with(Vec::new)
+      .push(corner);
+  }
+
+  let min_cos = crease_angle.map(|angle| angle.cos());
+
+  let mut new_positions = Vec::with_capacity(positions.len());
+  let mut new_uvs       = Vec::with_capacity(positions.len());
+  let mut normals       = Vec::with_capacity(positions.len());
+  // Vertices are shared as long as their normals match
+  let mut vertex_map: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+  let mut new_indices = Vec::with_capacity(indices.len());
+
+  for (corner, &idx) in indices.iter().enumerate() {
+    let face_normal = face_normals[corner / 3];
+
+    let mut sum: Vector3<f32> = na::zero();
+    for &other in &corners[&position_key(&positions[idx as usize])] {
+      let other_normal = face_normals[other / 3];
+      let smooth = min_cos.map(|min_cos| face_normal.dot(&other_normal) >= min_cos).unwrap_or(true);
+      if smooth {
+        sum += other_normal * weights[other];
+      }
+    }
+    let normal = if sum.norm() > 1e-12 { sum.normalize() } else { face_normal };
+
+    let key = (idx, position_key(&normal));
+    let new_idx = match vertex_map.get(&key) {
+      Some(&new_idx) => new_idx,
+      None => {
+        let new_idx = new_positions.len() as u32;
+        new_positions.push(positions[idx as usize]);
+        new_uvs.push(uvs[idx as usize]);
+        normals.push(normal);
+        vertex_map.insert(key, new_idx);
+        new_idx
+      },
+    };
+    new_indices.push(new_idx);
+  }
+
+  *indices   = new_indices;
+  *positions = new_positions;
+  *uvs       = new_uvs;
+  normals
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tobj;
+
+  use super::*;
+  use std::path::Path;
+
+  struct Fixture {
+    positions: Vec<Vector3<f32>>,
+    uvs:       Vec<[f32; 2]>,
+    indices:   Vec<u32>,
+  }
+
+  // Loads the first model of `file`, ignoring its normals
+  fn load(file: &str) -> Fixture {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(file);
+    let (models, _) = tobj::load_obj(&path).unwrap();
+    let mesh = &models[0].mesh;
+
+    let positions: Vec<_> = mesh.positions.chunks(3)
+      .map(|p| Vector3::new(p[0], p[1], p[2]))
+      .collect();
+    let mut uvs = vec![[0.0; 2]; positions.len()];
+    for (uv, t) in uvs.iter_mut().zip(mesh.texcoords.chunks(2)) {
+      *uv = [t[0], t[1]];
+    }
+
+    Fixture { positions: positions, uvs: uvs, indices: mesh.indices.clone() }
+  }
+
+  fn generate(file: &str, mode: NormalMode) -> (Fixture, Vec<Vector3<f32>>) {
+    let mut fixture = load(file);
+    let index_count = fixture.indices.len();
+    let normals = generate_normals(&mut fixture.positions, &mut fixture.uvs, &mut fixture.indices, mode);
+
+    assert_eq!(normals.len(), fixture.positions.len());
+    assert_eq!(fixture.uvs.len(), fixture.positions.len());
+    assert_eq!(fixture.indices.len(), index_count);
+    assert!(fixture.indices.iter().all(|&i| (i as usize) < fixture.positions.len()));
+    for normal in &normals {
+      assert!((normal.norm() - 1.0).abs() < 1e-4, "{:?} isn't unit length", normal);
+    }
+    (fixture, normals)
+  }
+
+  fn smooth(weighting: NormalWeighting, crease_angle: Option<f32>) -> NormalMode {
+    NormalMode::Smooth { weighting: weighting, crease_angle: crease_angle }
+  }
+
+  const MODES: [NormalMode; 5] = [
+    NormalMode::Flat,
+    NormalMode::Smooth { weighting: NormalWeighting::Area,  crease_angle: None },
+    NormalMode::Smooth { weighting: NormalWeighting::Angle, crease_angle: None },
+    NormalMode::Smooth { weighting: NormalWeighting::Area,  crease_angle: Some(1.0) },
+    NormalMode::Smooth { weighting: NormalWeighting::Angle, crease_angle: Some(1.0) },
+  ];
+
+  #[test]
+  fn cube_flat() {
+    let (fixture, normals) = generate("cube.obj", NormalMode::Flat);
+    assert_eq!(fixture.positions.len(), 36);
+    // Face normals of a cube are axis aligned
+    for normal in &normals {
+      let max = normal.x.abs().max(normal.y.abs()).max(normal.z.abs());
+      assert!((max - 1.0).abs() < 1e-5);
+    }
+  }
+
+  #[test]
+  fn cube_crease_keeps_hard_edges() {
+    for &weighting in &[NormalWeighting::Area, NormalWeighting::Angle] {
+      let (fixture, normals) = generate("cube.obj", smooth(weighting, Some(60f32.to_radians())));
+      assert_eq!(fixture.positions.len(), 24);
+
+      let (_, flat) = generate("cube.obj", NormalMode::Flat);
+      for normal in &normals {
+        assert!(flat.iter().any(|f| (*f - *normal).norm() < 1e-5));
+      }
+    }
+  }
+
+  #[test]
+  fn cube_angle_weighted_normals_are_diagonal() {
+    // Each corner's angles sum up to 90° on all three faces, no matter
+    // how the faces are triangulated
+    let (_, normals) = generate("cube.obj", smooth(NormalWeighting::Angle, None));
+    for normal in &normals {
+      for &c in &[normal.x, normal.y, normal.z] {
+        assert!((c.abs() - 1.0 / 3f32.sqrt()).abs() < 1e-4, "{:?}", normal);
+      }
+    }
+  }
+
+  #[test]
+  fn cube_all_modes() {
+    for &mode in &MODES {
+      generate("cube.obj", mode);
+    }
+  }
+
+  #[test]
+  fn teapot_all_modes() {
+    let original = load("teapot.obj");
+
+    for &mode in &MODES {
+      let (fixture, _) = generate("teapot.obj", mode);
+      match mode {
+        NormalMode::Flat =>
+          assert_eq!(fixture.positions.len(), original.indices.len()),
+        NormalMode::Smooth { crease_angle: None, .. } =>
+          assert_eq!(fixture.positions.len(), original.positions.len()),
+        NormalMode::Smooth { crease_angle: Some(_), .. } => {
+          assert!(fixture.positions.len() >= original.positions.len());
+          assert!(fixture.positions.len() < original.indices.len());
+        },
+      }
+    }
+  }
+}
@@ -0,0 +1,231 @@
+use super::components::*;
+use super::storage::*;
+
+/// Something that can take part in a query: a borrowed component
+/// storage, an optional one (`Maybe`) or a tuple of those.
+///
+/// Borrow the storages via `EntityManager::storage`/`storage_mut`
+/// first, then pass references to them to `EntityManager::join`:
+///
+/// ```ignore
+/// let velocities     = manager.storage::<Velocity>();
+/// let mut transforms = manager.storage_mut::<Transform>();
+/// for (entity, (transform, velocity)) in manager.join((&mut *transforms, &*velocities)) {
+///   transform.translate(velocity.linear);
+/// }
+/// ```
+pub trait Join {
+  type Item;
+
+  /// Whether `entity` has everything needed to produce an item.
+  fn matches(&self, entity: EntityId) -> bool;
+
+  /// Fetches the item for `entity`. Callers must make sure `entity`
+  /// matches and that no entity gets fetched twice, as mutable items
+  /// would alias otherwise.
+  unsafe fn fetch(&mut self, entity: EntityId) -> Self::Item;
+
+  /// Number of entities that can possibly match, `None` if any entity
+  /// can. Queries only look at these candidates.
+  fn candidates(&self) -> Option<usize> {
+    None
+  }
+
+  /// Index of the `n`th candidate entity, only called if `candidates`
+  /// returned `Some`.
+  fn candidate(&self, _n: usize) -> u32 {
+    unreachable!()
+  }
+}
+
+// The smaller of two candidate counts, `None` meaning unbounded
+fn fewer(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+  match (a, b) {
+    (Some(a), Some(b)) => Some(a.min(b)),
+    (a, None) => a,
+    (None, b) => b,
+  }
+}
+
+impl<'a, T> Join for &'a ComponentArray<T> {
+  type Item = &'a T;
+
+  fn matches(&self, entity: EntityId) -> bool {
+    self.contains(entity)
+  }
+
+  unsafe fn fetch(&mut self, entity: EntityId) -> Self::Item {
+    let storage: &'a ComponentArray<T> = *self;
+    &storage[entity]
+  }
+
+  fn candidates(&self) -> Option<usize> {
+    Some(self.len())
+  }
+
+  fn candidate(&self, n: usize) -> u32 {
+    self.owners()[n]
+  }
+}
+
+impl<'a, T> Join for &'a mut ComponentArray<T> {
+  type Item = &'a mut T;
+
+  fn matches(&self, entity: EntityId) -> bool {
+    self.contains(entity)
+  }
+
+  unsafe fn fetch(&mut self, entity: EntityId) -> Self::Item {
+    // Every entity is fetched at most once, so handing out references
+    // with the storage's lifetime doesn't alias (like `slice::IterMut`)
+    let component: *mut T = &mut self[entity];
+    &mut *component
+  }
+
+  fn candidates(&self) -> Option<usize> {
+    Some(self.len())
+  }
+
+  fn candidate(&self, n: usize) -> u32 {
+    self.owners()[n]
+  }
+}
+
+/// Makes a component optional: matches every entity and yields `None`
+/// if the entity lacks the component.
+pub struct Maybe<J>(pub J);
+
+impl<J: Join> Join for Maybe<J> {
+  type Item = Option<J::Item>;
+
+  fn matches(&self, _entity: EntityId) -> bool {
+    true
+  }
+
+  unsafe fn fetch(&mut self, entity: EntityId) -> Self::Item {
+    if self.0.matches(entity) {
+      Some(self.0.fetch(entity))
+    } else {
+      None
+    }
+  }
+}
+
+macro_rules! impl_join_tuple {
+  ( $( $name:ident )+ ) => {
+    impl<$( $name: Join ),+> Join for ( $( $name, )+ ) {
+      type Item = ( $( $name::Item, )+ );
+
+      #[allow(non_snake_case)]
+      fn matches(&self, entity: EntityId) -> bool {
+        let ( $( ref $name, )+ ) = *self;
+        $( $name.matches(entity) )&&+
+      }
+
+      #[allow(non_snake_case)]
+      unsafe fn fetch(&mut self, entity: EntityId) -> Self::Item {
+        let ( $( ref mut $name, )+ ) = *self;
+        ( $( $name.fetch(entity), )+ )
+      }
+
+      #[allow(non_snake_case)]
+      fn candidates(&self) -> Option<usize> {
+        let ( $( ref $name, )+ ) = *self;
+        let mut smallest = None;
+        $( smallest = fewer(smallest, $name.candidates()); )+
+        smallest
+      }
+
+      #[allow(non_snake_case)]
+      fn candidate(&self, n: usize) -> u32 {
+        // Pick the same member every time: the first smallest one
+        let smallest = self.candidates();
+        let ( $( ref $name, )+ ) = *self;
+        $(
+          if $name.candidates().is_some() && $name.candidates() == smallest {
+            return $name.candidate(n);
+          }
+        )+
+        unreachable!()
+      }
+    }
+  };
+}
+
+impl_join_tuple!(A);
+impl_join_tuple!(A B);
+impl_join_tuple!(A B C);
+impl_join_tuple!(A B C D);
+impl_join_tuple!(A B C D E);
+impl_join_tuple!(A B C D E F);
+impl_join_tuple!(A B C D E F G);
+impl_join_tuple!(A B C D E F G H);
+
+/// Iterator over all live entities matching a `Join`, yielding the
+/// entity together with its components. Only the entities of the
+/// smallest required storage get visited, joins of just `Maybe`s visit
+/// every live entity.
+pub struct Query<'a, J: Join> {
+  manager:    &'a EntityManager,
+  entities:   EntityIterator<'a>,
+  candidates: Option<usize>,
+  next:       usize,
+  without:    ComponentFlags,
+  join:       J,
+}
+
+impl<'a, J: Join> Query<'a, J> {
+  pub fn new(manager: &'a EntityManager, join: J) -> Self {
+    Query {
+      manager:    manager,
+      entities:   EntityManager::entity_iter(manager.entities(), FLAG_NONE),
+      candidates: join.candidates(),
+      next:       0,
+      without:    ComponentFlags::empty(),
+      join:       join,
+    }
+  }
+
+  /// Skips entities having component `T`.
+  pub fn without<T: Component>(mut self) -> Self {
+    if let Some(flag) = self.manager.flag::<T>() {
+      self.without.insert(flag);
+    }
+    self
+  }
+}
+
+impl<'a, J: Join> Iterator for Query<'a, J> {
+  type Item = (EntityId, J::Item);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      let entity = match self.candidates {
+        Some(count) => {
+          if self.next >= count {
+            return None;
+          }
+          let index = self.join.candidate(self.next);
+          self.next += 1;
+          match self.manager.entities().get(index) {
+            Some(entity) => entity,
+            None => continue,
+          }
+        },
+        None => match self.entities.next() {
+          Some(entity) => entity,
+          None => return None,
+        },
+      };
+
+      if self.manager.flags(entity).intersects(self.without) {
+        continue;
+      }
+      if self.join.matches(entity) {
+        // Storages own every entity index only once, so no entity gets
+        // fetched twice
+        return Some((entity, unsafe { self.join.fetch(entity) }));
+      }
+    }
+  }
+}
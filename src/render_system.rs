@@ -9,15 +9,14 @@ struct Uniforms {
   pickingId:         u32,
   _padding1:         [u32; 3],
   modelMatrix:       [[f32; 4]; 4],
+  // Only the upper 3x3 part is used (as `mat3` in the shaders)
   normalMatrix:      [[f32; 4]; 4],
   // Material:          &'a Material,
-  // diffuseTexture:    &'a gl::texture::SrgbTexture2d,
+  // diffuseTexture:    &'a Texture,
   // hasDiffuseTexture: bool,
 
   viewMatrix:        [[f32; 4]; 4],
   projectionMatrix:  [[f32; 4]; 4],
-  lightPosition:     [f32; 3],
-  _padding2:         u32,
   cameraPosition:    [f32; 3],
 }
 
@@ -27,21 +26,92 @@ implement_uniform_block!(Uniforms,
                          normalMatrix,
                          viewMatrix,
                          projectionMatrix,
-                         lightPosition,
                          cameraPosition);
 
+/// Maximum number of lights shading a frame, has to match `MAX_LIGHTS`
+/// in the shaders. Additional lights are ignored.
+pub const MAX_LIGHTS: usize = 8;
+
+// Light kinds as encoded in `LightData::position.w`
+const LIGHT_POINT:       f32 = 0.0;
+const LIGHT_DIRECTIONAL: f32 = 1.0;
+const LIGHT_SPOT:        f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(non_snake_case)]
+struct LightData {
+  // w: kind
+  position:    [f32; 4],
+  direction:   [f32; 4],
+  // color * intensity
+  color:       [f32; 4],
+  // constant, linear, quadratic
+  attenuation: [f32; 4],
+  // cos(inner), cos(outer)
+  cone:        [f32; 4],
+  // shadow map index (-1 for none), bias, 1 for cube maps, far plane
+  shadow:      [f32; 4],
+  shadowMatrix: [[f32; 4]; 4],
+}
+
+implement_uniform_block!(LightData, position, direction, color, attenuation, cone,
+                         shadow, shadowMatrix);
+
+impl LightData {
+  fn new(light: &Light, world: &Matrix4<f32>) -> Self {
+    let position  = Vector3::new(world[(0,3)], world[(1,3)], world[(2,3)]);
+    let direction = forward(world);
+
+    let (kind, cone) = match light.kind {
+      LightKind::Point => (LIGHT_POINT, [0.0; 4]),
+      LightKind::Directional => (LIGHT_DIRECTIONAL, [0.0; 4]),
+      LightKind::Spot { inner_angle, outer_angle } =>
+        (LIGHT_SPOT, [inner_angle.cos(), outer_angle.cos(), 0.0, 0.0]),
+    };
+    let color = light.color * light.intensity;
+    let a = light.attenuation;
+
+    LightData {
+      position:    [position.x, position.y, position.z, kind],
+      direction:   [direction.x, direction.y, direction.z, 0.0],
+      color:       [color.x, color.y, color.z, 1.0],
+      attenuation: [a.constant, a.linear, a.quadratic, 0.0],
+      cone:        cone,
+      shadow:      [-1.0, 0.0, 0.0, 0.0],
+      shadowMatrix: Matrix4::identity().as_uniform(),
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+#[allow(non_snake_case)]
+struct Lights {
+  lightCount: u32,
+  _padding:   [u32; 3],
+  lights:     [LightData; MAX_LIGHTS],
+}
+
+implement_uniform_block!(Lights, lightCount, lights);
+
 pub struct RenderSystem {
-  empty_texture: gl::texture::SrgbTexture2d,
+  empty_texture: Texture,
   uniform_buffer: gl::uniforms::UniformBuffer<Uniforms>,
+  lights_buffer: gl::uniforms::UniformBuffer<Lights>,
+  shadow_system: ShadowSystem,
   pub render_wireframe: bool,
+  /// Draws the faces of the first point light's shadow cube map.
+  pub debug_shadow_faces: bool,
 }
 
 impl RenderSystem {
   pub fn new<F: Facade>(f: &F) -> Self {
     RenderSystem {
-      empty_texture: gl::texture::SrgbTexture2d::empty(f, 0, 0).unwrap(),
+      empty_texture: Texture::solid(f, [255; 4], TextureSettings::default()),
       uniform_buffer: gl::uniforms::UniformBuffer::empty_dynamic(f).unwrap(),
+      lights_buffer: gl::uniforms::UniformBuffer::empty_dynamic(f).unwrap(),
+      shadow_system: ShadowSystem::new(f, 2048),
       render_wireframe: false,
+      debug_shadow_faces: false,
     }
   }
 
@@ -51,7 +121,8 @@ impl RenderSystem {
                        picking_surface: &mut PS,
                        // TODO: Pass via `World`
                        resources: &ResourceManager,
-                       world_uniforms: &WorldUniforms)
+                       world_uniforms: &WorldUniforms,
+                       alpha: f32)
     where S: gl::Surface, PS: gl::Surface {
     // TODO: Pull out somewhere
     let mut params = gl::DrawParameters {
@@ -78,68 +149,139 @@ impl RenderSystem {
       let mut x = self.uniform_buffer.map();
       // x.viewMatrix       = world_uniforms.camera_matrix.as_uniform();
       // x.projectionMatrix = world_uniforms.projection_matrix.as_uniform();
-      // x.lightPosition    = world_uniforms.light_position.as_uniform();
       // x.cameraPosition   = world_uniforms.camera_position.as_uniform();
       x.viewMatrix       = world_uniforms.camera_matrix.as_uniform();
       x.projectionMatrix = world_uniforms.projection_matrix.as_uniform();
-      x.lightPosition    = world_uniforms.light_position.as_uniform();
       x.cameraPosition   = world_uniforms.camera_position.as_uniform();
     }
-    
-    // Iterate over all entities with geometries
-    for entity in EntityManager::entity_iter(&manager.entities, FLAG_GEOMETRY) {
-      let g = manager.geometries[entity];
-      let flags = manager.entities[entity];
-      
-      let p = manager.positions[entity];
-      let pickable_id = if flags.contains(FLAG_PICKABLE) {
-        Some(manager.pickables[entity])
-      } else {
-        None
-      };
-      
 
-      let model_mat = {
-        let mut m = p.as_matrix();
-        if flags.contains(FLAG_ROTATION) {
-          m *= manager.rotations[entity].as_matrix();
-        }
-        if flags.contains(FLAG_SCALE) {
-          m *= manager.scales[entity].as_matrix();
+    let geometries = manager.storage::<Geometry>();
+    let pickables  = manager.storage::<Pickable>();
+    let shadows    = manager.storage::<Shadows>();
+
+    // Gather all entities with geometries
+    let drawables: Vec<_> = manager.join((&*geometries, Maybe(&*pickables), Maybe(&*shadows)))
+      .map(|(entity, (g, pickable_id, shadows))| {
+        let world = manager.interpolated_world_transform(entity, alpha)
+          .unwrap_or(WorldTransform::new(Matrix4::identity()));
+        (g, pickable_id, world, shadows.cloned().unwrap_or_default())
+      })
+      .collect();
+
+    // Collect lights (once per frame)
+    let mut light_matrices = Vec::new();
+    let mut point_shadows  = Vec::new();
+    {
+      let lights = manager.storage::<Light>();
+      let mut x = self.lights_buffer.map();
+      let mut count = 0;
+      for (entity, light) in manager.join(&*lights).take(MAX_LIGHTS) {
+        let world = manager.interpolated_world_transform(entity, alpha)
+          .map(|w| w.matrix())
+          .unwrap_or(Matrix4::identity());
+        x.lights[count] = LightData::new(light, &world);
+
+        match (light.kind, light.shadows) {
+          (LightKind::Point, Some(settings)) => if point_shadows.len() < MAX_POINT_SHADOW_MAPS {
+            x.lights[count].shadow = [point_shadows.len() as f32, settings.bias, 1.0, settings.far];
+            point_shadows.push(PointShadow {
+              position: Vector3::new(world[(0,3)], world[(1,3)], world[(2,3)]),
+              settings: settings,
+            });
+          },
+          (_, Some(settings)) => if light_matrices.len() < MAX_SHADOW_MAPS {
+            if let Some(m) = light.shadow_matrix(&world) {
+              x.lights[count].shadow = [light_matrices.len() as f32, settings.bias, 0.0, settings.far];
+              x.lights[count].shadowMatrix = m.as_uniform();
+              light_matrices.push(m);
+            }
+          },
+          (_, None) => (),
         }
-        m
-      };
+        count += 1;
+      }
+      x.lightCount = count as u32;
+    }
+
+    // Render shadow maps
+    {
+      let casters: Vec<_> = drawables.iter()
+        .filter(|&&(_, _, _, shadows)| shadows.cast)
+        .map(|&(g, _, ref world, _)| (g, world.matrix()))
+        .collect();
+      self.shadow_system.render(resources, &casters, &light_matrices, &point_shadows);
+    }
 
+    // The shaders compare depths themselves, filtering would blur them
+    let shadow_sampler = gl::uniforms::Sampler::new(self.shadow_system.maps())
+      .magnify_filter(gl::uniforms::MagnifySamplerFilter::Nearest)
+      .minify_filter(gl::uniforms::MinifySamplerFilter::Nearest);
+    let point_shadow_samplers: Vec<_> = (0..MAX_POINT_SHADOW_MAPS).map(|idx| {
+      gl::uniforms::Sampler::new(self.shadow_system.cube_map(idx))
+        .magnify_filter(gl::uniforms::MagnifySamplerFilter::Nearest)
+        .minify_filter(gl::uniforms::MinifySamplerFilter::Nearest)
+    }).collect();
 
-      let normal_mat = model_mat; // No idea why this doesn't need inverse()
+    let empty_texture = &self.empty_texture;
+    let lookup_texture = |name: &Option<String>| {
+      name.as_ref().and_then(|name| resources.textures.get(name)).unwrap_or(empty_texture)
+    };
+
+    for &(g, pickable_id, ref world, shadows) in drawables.iter() {
+      let model_mat  = world.matrix();
+      let normal_mat = world.normal_matrix().to_homogeneous();
 
       let ref program = resources.programs[&g.program];
 
       for (_name, mesh) in resources.meshes[&g.geometry].meshes.iter() {
-        let texture = mesh.texture.as_ref().and_then(|name| {
-          resources.textures.get(name)
-        }).unwrap_or(&self.empty_texture);
+        let texture = lookup_texture(&mesh.texture);
+        let pbr = &mesh.pbr_textures;
 
         // Update `model` uniforms, once per draw-call
         {
           let mut x = self.uniform_buffer.map();
-          x.pickingId = if flags.contains(FLAG_PICKABLE) {
-            manager.pickables[entity].0
-          } else {
-            0
-          };
+          x.pickingId = pickable_id.map(|p| p.0).unwrap_or(0);
           x.modelMatrix = model_mat.as_uniform();
           x.normalMatrix = normal_mat.as_uniform();
         }
 
         let uniforms = uniform! {
           Uniforms:          &*self.uniform_buffer,
+          Lights:            &*self.lights_buffer,
           Material:          &mesh.material,
           diffuseTexture:    texture,
           hasDiffuseTexture: mesh.texture.is_some(),
+          normalTexture:     lookup_texture(&mesh.normal_map),
+          hasNormalTexture:  mesh.normal_map.is_some(),
+          ambientTexture:      lookup_texture(&mesh.textures.ambient),
+          hasAmbientTexture:   mesh.textures.ambient.is_some(),
+          specularTexture:     lookup_texture(&mesh.textures.specular),
+          hasSpecularTexture:  mesh.textures.specular.is_some(),
+          shininessTexture:    lookup_texture(&mesh.textures.shininess),
+          hasShininessTexture: mesh.textures.shininess.is_some(),
+          alphaTexture:        lookup_texture(&mesh.textures.alpha),
+          hasAlphaTexture:     mesh.textures.alpha.is_some(),
+          // The PBR program's `emissiveTexture` is bound separately
+          emissionTexture:     lookup_texture(&mesh.textures.emissive),
+          hasEmissionTexture:  mesh.textures.emissive.is_some(),
+          PbrMaterial:         &mesh.pbr_material,
+          baseColorTexture:    lookup_texture(&pbr.base_color),
+          hasBaseColorTexture: pbr.base_color.is_some(),
+          metallicTexture:     lookup_texture(&pbr.metallic),
+          hasMetallicTexture:  pbr.metallic.is_some(),
+          roughnessTexture:    lookup_texture(&pbr.roughness),
+          hasRoughnessTexture: pbr.roughness.is_some(),
+          emissiveTexture:     lookup_texture(&pbr.emissive),
+          hasEmissiveTexture:  pbr.emissive.is_some(),
+          occlusionTexture:    lookup_texture(&pbr.occlusion),
+          hasOcclusionTexture: pbr.occlusion.is_some(),
+          shadowMaps:        shadow_sampler,
+          pointShadowMap0:   point_shadow_samplers[0],
+          pointShadowMap1:   point_shadow_samplers[1],
+          receiveShadows:    shadows.receive,
         };
 
-        surface.draw((&mesh.positions, &mesh.normals),
+        surface.draw((&mesh.positions, &mesh.normals, &mesh.tangents),
                      &mesh.indices,
                      program,
                      &uniforms,
@@ -174,5 +316,9 @@ impl RenderSystem {
                    &gl::DrawParameters::default())
         .unwrap();
     }
+
+    if self.debug_shadow_faces && !point_shadows.is_empty() {
+      self.shadow_system.render_debug_faces(surface, resources, 0);
+    }
   }
 }
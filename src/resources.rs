@@ -1,15 +1,107 @@
 extern crate tobj;
-extern crate image;
 
 use std::collections::HashMap;
 use glium as gl;
 use nalgebra as na;
 use super::geometry::*;
+use super::normals::*;
+use super::tangents::*;
+use super::texture::*;
 
-use std::fs::File;
-use std::io::BufReader;
 use std::path::Path;
 use std::fmt;
+use std::error::Error;
+use std::io;
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Debug)]
+pub enum ResourceError {
+  Io { file: String, error: io::Error },
+  /// The file's content couldn't be understood.
+  Parse { file: String, message: String },
+  /// glium doesn't tell which stage failed, so `file` names both shaders.
+  ShaderCompile { file: String, log: String },
+  /// Creating a GL object failed.
+  Gl(String),
+}
+
+impl ResourceError {
+  fn io(file: &str, error: io::Error) -> Self {
+    ResourceError::Io { file: file.to_string(), error: error }
+  }
+
+  fn gl<E: fmt::Debug>(error: E) -> Self {
+    ResourceError::Gl(format!("{:?}", error))
+  }
+}
+
+impl fmt::Display for ResourceError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ResourceError::Io { ref file, ref error } =>
+        write!(f, "Can't read {}: {}", file, error),
+      ResourceError::Parse { ref file, ref message } =>
+        write!(f, "Can't parse {}: {}", file, message),
+      ResourceError::ShaderCompile { ref file, ref log } =>
+        write!(f, "Can't compile {}:\n{}", file, log),
+      ResourceError::Gl(ref message) =>
+        write!(f, "GL error: {}", message),
+    }
+  }
+}
+
+impl Error for ResourceError {
+  fn description(&self) -> &str {
+    match *self {
+      ResourceError::Io { .. }            => "I/O error",
+      ResourceError::Parse { .. }         => "parse error",
+      ResourceError::ShaderCompile { .. } => "shader compilation error",
+      ResourceError::Gl(_)                => "GL error",
+    }
+  }
+
+  fn cause(&self) -> Option<&Error> {
+    match *self {
+      ResourceError::Io { ref error, .. } => Some(error),
+      _ => None,
+    }
+  }
+}
+
+fn read_to_string(file: &str) -> Result<String, ResourceError> {
+  let mut src = String::new();
+  File::open(file)
+    .and_then(|mut f| f.read_to_string(&mut src))
+    .map_err(|e| ResourceError::io(file, e))?;
+  Ok(src)
+}
+
+/// Reads shader `file`, replacing `#include "other.glsl"` lines with
+/// the content of `other.glsl`, relative to `file`.
+fn read_shader(file: &str) -> Result<String, ResourceError> {
+  let src = read_to_string(file)?;
+  let dir = Path::new(file).parent().unwrap_or(Path::new(""));
+
+  let mut out = String::with_capacity(src.len());
+  for line in src.lines() {
+    if line.trim_left().starts_with("#include") {
+      let name = line.trim_left()["#include".len()..].trim().trim_matches('"');
+      if name.is_empty() {
+        return Err(ResourceError::Parse {
+          file: file.to_string(),
+          message: format!("Missing file name in {:?}", line),
+        });
+      }
+      let included = dir.join(name).to_string_lossy().into_owned();
+      out.push_str(&read_shader(&included)?);
+    } else {
+      out.push_str(line);
+    }
+    out.push('\n');
+  }
+  Ok(out)
+}
 
 pub struct MultiMesh {
   pub meshes: HashMap<String, Mesh>
@@ -18,19 +110,45 @@ pub struct MultiMesh {
 pub struct Mesh {
   pub positions: gl::VertexBuffer<Vertex>,
   pub normals:   gl::VertexBuffer<Normal>,
+  pub tangents:  gl::VertexBuffer<Tangent>,
   pub indices:   gl::index::IndexBuffer<u32>,
   // TODO: Move to `MultiMesh`
   pub material:  gl::uniforms::UniformBuffer<Material>,
   pub texture:   Option<String>,
+  pub normal_map: Option<String>,
+  pub textures:  MaterialTextures,
+  pub pbr_material: gl::uniforms::UniformBuffer<PbrMaterial>,
+  pub pbr_textures: PbrTextures,
+}
+
+/// Names of the textures of a `Material` besides the diffuse one, keys
+/// into `ResourceManager::textures`.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialTextures {
+  pub ambient:   Option<String>,
+  pub specular:  Option<String>,
+  pub shininess: Option<String>,
+  pub alpha:     Option<String>,
+  pub emissive:  Option<String>,
 }
 
-// TODO: Support `TextureAny`
-type Texture = gl::texture::SrgbTexture2d;
+/// Names of the textures of a `PbrMaterial`, keys into
+/// `ResourceManager::textures`.
+#[derive(Debug, Clone, Default)]
+pub struct PbrTextures {
+  pub base_color: Option<String>,
+  pub metallic:   Option<String>,
+  pub roughness:  Option<String>,
+  pub emissive:   Option<String>,
+  pub occlusion:  Option<String>,
+}
 
 pub struct ResourceManager {
   pub meshes:    HashMap<&'static str, MultiMesh>,
   pub programs:  HashMap<&'static str, gl::Program>,
   pub textures:  HashMap<String, Texture>,
+  /// Used for meshes without normals.
+  pub normal_mode: NormalMode,
 }
 
 impl ResourceManager {
@@ -39,51 +157,49 @@ impl ResourceManager {
       meshes:    HashMap::new(),
       programs:  HashMap::new(),
       textures:  HashMap::new(),
+      normal_mode: NormalMode::default(),
     }
   }
 
+  /// Compiles `vertex` and `fragment` as program `name`. Both may
+  /// `#include` other files relative to themselves.
   pub fn compile_shader<P>(&mut self,
                            display: &gl::Display,
                            name: &'static str,
                            vertex: P,
-                           fragment: P)
+                           fragment: P) -> Result<(), ResourceError>
     where P: AsRef<Path>+fmt::Display {
-    use std::fs::File;
-    use std::io::Read;
-    let vertex_src = {
-      let mut f = File::open(&vertex).unwrap();
-      let mut src = String::new();
-      f.read_to_string(&mut src).unwrap();
-      src
-    };
-
-    let fragment_src = {
-      let mut f = File::open(&fragment).unwrap();
-      let mut src = String::new();
-      f.read_to_string(&mut src).unwrap();
-      src
-    };
+    let vertex_src   = read_shader(&vertex.to_string())?;
+    let fragment_src = read_shader(&fragment.to_string())?;
 
     let program = gl::Program::from_source(display,
                                            &vertex_src,
                                            &fragment_src,
-                                           None).unwrap();
+                                           None)
+      .map_err(|e| match e {
+        gl::program::ProgramCreationError::CompilationError(log) |
+        gl::program::ProgramCreationError::LinkingError(log) =>
+          ResourceError::ShaderCompile { file: format!("{}, {}", vertex, fragment), log: log },
+        e => ResourceError::gl(e),
+      })?;
 
     println!("compiling shader {:?}, id: {:?}", name, program);
 
     self.programs.insert(name, program);
+    Ok(())
   }
 
   fn load_mesh(&mut self, display: &gl::Display,
+               base_dir: &Path,
                model: tobj::Model,
                // TODO: Pass our own `Material`
-               materials: &HashMap<usize, tobj::Material>) -> Mesh {
+               materials: &HashMap<usize, tobj::Material>) -> Result<Mesh, ResourceError> {
     println!("model.name = {}", model.name);
 
     let mesh = &model.mesh;
     assert!(mesh.positions.len() % 3 == 0);
 
-    let indices = mesh.indices.clone();
+    let mut indices = mesh.indices.clone();
 
     let mut vertices = Vec::with_capacity(mesh.positions.len()/3);
     for f in 0..mesh.positions.len() / 3 {
@@ -94,110 +210,176 @@ impl ResourceManager {
     }
 
 
-    let mut normals = vec![na::zero(); vertices.len()];
+    let mut uvs = vec![[0.0; 2]; vertices.len()];
+    if mesh.texcoords.len() > 0 {
+      println!("Got {} texture coordinates", mesh.texcoords.len());
+      for f in 0..mesh.texcoords.len()/2 {
+        uvs[f] = [mesh.texcoords[f*2],
+                  mesh.texcoords[f*2 + 1]];
+      }
+    }
 
-    if mesh.normals.len() > 0 {
+    let normals = if mesh.normals.len() > 0 {
       println!("Got normals in obj file");
+      let mut normals = vec![na::zero(); vertices.len()];
       for f in 0..mesh.normals.len() / 3 {
         let normal = na::Vector3::new(mesh.normals[3 * f],
                                       mesh.normals[3 * f + 1],
                                       mesh.normals[3 * f + 2]);
         normals[f] = normal;
       }
+      normals
     } else {
-      println!("Calculating our own normals :-(");
-      // Go over all Tris and calculate normals ourselves
-      for f in 0..indices.len()/3 {
-        let idx1 = indices[3*f] as usize;
-        let idx2 = indices[3*f+1] as usize;
-        let idx3 = indices[3*f+2] as usize;
-
-        let v1 = vertices[idx1];
-        let v2 = vertices[idx2];
-        let v3 = vertices[idx3];
-        let normal  = na::normalize(&(v2-v1).cross(&(v3-v1)));
-
-        normals[idx1] = normal;
-        normals[idx2] = normal;
-        normals[idx3] = normal;
-      }
-    }
+      println!("Calculating our own normals ({:?})", self.normal_mode);
+      generate_normals(&mut vertices, &mut uvs, &mut indices, self.normal_mode)
+    };
 
     println!("vertices.len: {}", vertices.len());
     println!("indices.len: {}", indices.len());
     println!("normals.len: {}", normals.len());
 
-    let mut vertices: Vec<_> = vertices.into_iter().map(Vertex::from).collect();
-    let normals: Vec<_> = normals.into_iter().map(Normal::from).collect();
+    let tangents = generate_tangents(&vertices, &normals, &uvs, &indices);
 
-    if mesh.texcoords.len() > 0 {
-      println!("Got {} texture coordinates", mesh.texcoords.len());
-      for f in 0..mesh.texcoords.len()/2 {
-        vertices[f].uv = [mesh.texcoords[f*2],
-                          mesh.texcoords[f*2 + 1]];
-      }
+    let mut vertices: Vec<_> = vertices.into_iter().map(Vertex::from).collect();
+    for (vertex, uv) in vertices.iter_mut().zip(uvs.into_iter()) {
+      vertex.uv = uv;
     }
+    let normals: Vec<_>  = normals.into_iter().map(Normal::from).collect();
+    let tangents: Vec<_> = tangents.into_iter().map(Tangent::from).collect();
 
-    let positions = gl::VertexBuffer::new(display, &vertices).unwrap();
-    let normals   = gl::VertexBuffer::new(display, &normals).unwrap();
-    let indices   = gl::index::IndexBuffer::new(display, gl::index::PrimitiveType::TrianglesList, &indices).unwrap();
+    let positions = gl::VertexBuffer::new(display, &vertices).map_err(ResourceError::gl)?;
+    let normals   = gl::VertexBuffer::new(display, &normals).map_err(ResourceError::gl)?;
+    let tangents  = gl::VertexBuffer::new(display, &tangents).map_err(ResourceError::gl)?;
+    let indices   = gl::index::IndexBuffer::new(display, gl::index::PrimitiveType::TrianglesList, &indices)
+      .map_err(ResourceError::gl)?;
 
-    let (mut material, texture) = {
+    let (mut material, texture, normal_map, textures, pbr_material, pbr_textures) = {
       if let Some(material) = model.mesh.material_id.and_then(|id| materials.get(&id)) {
-        let texture = match material.diffuse_texture.as_ref() {
-          "" => None,
-          s => {
-            if self.textures.get(s).is_none() {
-              self.load_texture(display, &s);
-            }
-            Some(s.to_string())
-          },
+        let srgb   = TextureSettings::default();
+        let linear = TextureSettings::linear();
+
+        let texture = self.texture_ref(display, base_dir, &material.diffuse_texture, srgb);
+
+        // `norm` is the usual statement for tangent space normal maps,
+        // but many exporters write them as bump maps. tobj doesn't
+        // parse either.
+        let normal_map = ["norm", "map_bump", "map_Bump", "bump"].iter()
+          .filter_map(|key| material.unknown_param.get(*key))
+          .filter_map(|value| value.split_whitespace().last())
+          .next()
+          .and_then(|file| self.texture_ref(display, base_dir, file, linear));
+
+        // tobj parses `map_Ns` into `normal_texture`
+        let textures = MaterialTextures {
+          ambient:   self.texture_ref(display, base_dir, &material.ambient_texture, srgb),
+          specular:  self.texture_ref(display, base_dir, &material.specular_texture, srgb),
+          shininess: self.texture_ref(display, base_dir, &material.normal_texture, linear),
+          alpha:     self.texture_ref(display, base_dir, &material.dissolve_texture, linear),
+          emissive:  material.unknown_param.get("map_Ke")
+            .and_then(|value| value.split_whitespace().last())
+            .and_then(|file| self.texture_ref(display, base_dir, file, srgb)),
+        };
+
+        // Blender exports the Principled BSDF's maps as unofficial MTL
+        // statements, which end up in `unknown_param`
+        let pbr_textures = {
+          let mut map = |key: &str| {
+            let file = material.unknown_param.get(key)
+              .and_then(|value| value.split_whitespace().last())
+              .unwrap_or("");
+            self.texture_ref(display, base_dir, file, linear)
+          };
+          PbrTextures {
+            base_color: texture.clone(),
+            metallic:   map("map_Pm"),
+            roughness:  map("map_Pr"),
+            emissive:   textures.emissive.clone(),
+            occlusion:  map("map_ao"),
+          }
         };
-        
-        (Material::from(material.clone()), texture)
+
+        (Material::from(material.clone()), texture, normal_map, textures, PbrMaterial::from(material), pbr_textures)
       } else {
         (Material {
           ambient:   [1.0; 4],
           diffuse:   [1.0; 4],
           specular:  [1.0; 4],
           shininess: 1.0,
-        }, None)
+        }, None, None, MaterialTextures::default(), PbrMaterial::default(), PbrTextures::default())
       }
     };
 
     // Override ambient color as Blender only exports white.
     material.ambient = [0.0; 4];
 
-    let material = gl::uniforms::UniformBuffer::new(display, material.into()).unwrap();
+    let material = gl::uniforms::UniformBuffer::new(display, material.into()).map_err(ResourceError::gl)?;
+    let pbr_material = gl::uniforms::UniformBuffer::new(display, pbr_material).map_err(ResourceError::gl)?;
 
-    Mesh {
+    Ok(Mesh {
       positions: positions,
       normals:   normals,
+      tangents:  tangents,
       indices:   indices,
       material:  material,
       texture:   texture.map(|s| s.to_string()),
+      normal_map: normal_map,
+      textures:  textures,
+      pbr_material: pbr_material,
+      pbr_textures: pbr_textures,
+    })
+  }
+
+  /// Loads texture `file`, relative to `base_dir`, unless it's already
+  /// loaded and returns its name. `None` for empty file names. Textures
+  /// that fail to load get a magenta texture instead, so a model with a
+  /// broken material still shows up.
+  fn texture_ref<F>(&mut self, facade: &F, base_dir: &Path, file: &str,
+                    settings: TextureSettings) -> Option<String>
+    where F: gl::backend::Facade {
+    match file {
+      "" => None,
+      s => {
+        let path = base_dir.join(s).to_string_lossy().into_owned();
+        let name = Self::texture_name(&path, &settings);
+        if self.textures.get(&name).is_none() {
+          let texture = Texture::load(facade, &path, settings).unwrap_or_else(|e| {
+            println!("Failed to load texture: {}", e);
+            Texture::fallback(facade)
+          });
+          self.textures.insert(name.clone(), texture);
+        }
+        Some(name)
+      },
     }
   }
 
   pub fn load_obj<P>(&mut self,
                      display: &gl::Display,
                      name: &'static str,
-                     path: P)
+                     path: P) -> Result<(), ResourceError>
     where P: AsRef<Path>+fmt::Display {
     println!("Loading {} from {}", name, path);
 
-    let obj = tobj::load_obj(path.as_ref());
-    let (models, materials) = obj.unwrap();
-    
+    // tobj doesn't keep the I/O error around
+    File::open(&path).map_err(|e| ResourceError::io(&path.to_string(), e))?;
+
+    let (models, materials) = tobj::load_obj(path.as_ref())
+      .map_err(|e| ResourceError::Parse { file: path.to_string(), message: format!("{:?}", e) })?;
+
     let materials = materials.into_iter().enumerate().collect();
-    
-    let models = models.into_iter()
-      .map(|model| {
-        (model.name.to_string(), self.load_mesh(display, model, &materials))
-      }).collect();
+
+    // tobj looks up the MTL file next to the OBJ, so do the same for
+    // the textures it references
+    let base_dir = path.as_ref().parent().unwrap_or(Path::new("")).to_path_buf();
+
+    let mut meshes = HashMap::new();
+    for model in models {
+      let model_name = model.name.to_string();
+      meshes.insert(model_name, self.load_mesh(display, &base_dir, model, &materials)?);
+    }
 
     self.meshes.insert(name, MultiMesh {
-      meshes: models,
+      meshes: meshes,
     });
     
     // // TODO: Use model.name for our name
@@ -294,46 +476,61 @@ impl ResourceManager {
     // material.ambient = [0.0; 4];
 
     // let material = gl::uniforms::UniformBuffer::new(display, material.into()).unwrap();
+
+    Ok(())
   }
 
-  pub fn make_axis_object<F: gl::backend::Facade>(&mut self, display: &F, name: &'static str) {
+  pub fn make_axis_object<F: gl::backend::Facade>(&mut self, display: &F, name: &'static str)
+                                                  -> Result<(), ResourceError> {
     let vertices = [(0.0, 0.0, 0.0).into(),
                     (1.0, 0.0, 0.0).into(),
                     (0.0, 1.0, 0.0).into(),
                     (0.0, 0.0, 1.0).into()];
-    let positions = gl::VertexBuffer::new(display, &vertices).unwrap();
-    let normals = gl::VertexBuffer::empty(display, 0).unwrap();
+    let positions = gl::VertexBuffer::new(display, &vertices).map_err(ResourceError::gl)?;
+    let normals = gl::VertexBuffer::empty(display, 0).map_err(ResourceError::gl)?;
+    let tangents = gl::VertexBuffer::empty(display, 0).map_err(ResourceError::gl)?;
     // let indices   = gl::index::NoIndices(gl::index::PrimitiveType::LinesList);
     let indices   = gl::index::IndexBuffer::new(display,
                                                 gl::index::PrimitiveType::LinesList,
-                                                &[0,1, 0,2, 0,3]).unwrap();
+                                                &[0,1, 0,2, 0,3]).map_err(ResourceError::gl)?;
 
     let mesh = Mesh {
       positions: positions,
       normals: normals,
+      tangents: tangents,
       indices: indices,
-      material: gl::uniforms::UniformBuffer::empty(display).unwrap(),
-      texture: None
+      material: gl::uniforms::UniformBuffer::empty(display).map_err(ResourceError::gl)?,
+      texture: None,
+      normal_map: None,
+      textures: MaterialTextures::default(),
+      pbr_material: gl::uniforms::UniformBuffer::empty(display).map_err(ResourceError::gl)?,
+      pbr_textures: PbrTextures::default(),
     };
     let mut meshes = HashMap::new();
     meshes.insert("axis".to_string(), mesh);
     self.meshes.insert(name, MultiMesh { meshes: meshes, });
+    Ok(())
+  }
+
+  /// Name of the texture loaded from `file` with `settings`. The same
+  /// file may be used both as color and as data texture, so linear
+  /// textures get their own name.
+  pub fn texture_name(file: &str, settings: &TextureSettings) -> String {
+    if settings.srgb {
+      file.to_string()
+    } else {
+      format!("{} (linear)", file)
+    }
   }
 
-  pub fn load_texture<F>(&mut self, facade: &F, file: &str)
+  /// Loads `file` as texture named `texture_name(file, &settings)`. Use
+  /// `Texture::fallback` to show missing textures instead of failing.
+  pub fn load_texture<F>(&mut self, facade: &F, file: &str, settings: TextureSettings)
+                         -> Result<(), ResourceError>
     where F: gl::backend::Facade {
-    let texture = {
-      File::open(file).ok()
-        .and_then(|file| image::load(BufReader::new(file), image::PNG).ok())
-        .map(|image| image.to_rgba())
-        .map(|image| {
-          let size = image.dimensions();
-          println!("Loaded {} as image with size {:?}", file, size);
-          let image = gl::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(), size);
-          gl::texture::SrgbTexture2d::new(facade, image).unwrap()
-        }).unwrap()
-    };
-    self.textures.insert(file.to_string(), texture);
+    let texture = Texture::load(facade, file, settings)?;
+    self.textures.insert(Self::texture_name(file, &settings), texture);
+    Ok(())
   }
 }
 
@@ -350,3 +547,30 @@ impl From<tobj::Material> for Material {
       }
     }
   }
+
+impl<'a> From<&'a tobj::Material> for PbrMaterial {
+  fn from(m: &'a tobj::Material) -> Self {
+    // First number(s) of an unofficial MTL statement
+    let floats = |key: &str| -> Vec<f32> {
+      m.unknown_param.get(key)
+        .map(|value| value.split_whitespace().filter_map(|v| v.parse().ok()).collect())
+        .unwrap_or(Vec::new())
+    };
+
+    let d = m.diffuse;
+    let metallic = floats("Pm").get(0).cloned().unwrap_or(0.0);
+    // Fall back to the Blinn-Phong exponent's usual roughness mapping
+    let roughness = floats("Pr").get(0).cloned()
+      .unwrap_or((2.0 / (m.shininess + 2.0)).sqrt());
+    let ke = floats("Ke");
+    let emissive = if ke.len() >= 3 { [ke[0], ke[1], ke[2], 1.0] } else { [0.0; 4] };
+
+    PbrMaterial {
+      base_color: [d[0], d[1], d[2], m.dissolve],
+      emissive:   emissive,
+      metallic:   metallic,
+      roughness:  roughness,
+      occlusion:  1.0,
+    }
+  }
+}
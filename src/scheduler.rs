@@ -0,0 +1,458 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+
+use rayon;
+
+use super::components::EntityManager;
+use super::storage::Component;
+use super::Millis;
+
+/// Set of component types a system reads and writes.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+  reads:  Vec<TypeId>,
+  writes: Vec<TypeId>,
+}
+
+impl Access {
+  pub fn new() -> Self {
+    Access::default()
+  }
+
+  pub fn read<T: Component>(mut self) -> Self {
+    self.reads.push(TypeId::of::<T>());
+    self
+  }
+
+  pub fn write<T: Component>(mut self) -> Self {
+    self.writes.push(TypeId::of::<T>());
+    self
+  }
+
+  /// Two accesses conflict if either writes a component the other
+  /// one reads or writes.
+  pub fn conflicts_with(&self, other: &Access) -> bool {
+    self.writes.iter().any(|t| other.reads.contains(t) || other.writes.contains(t))
+      || other.writes.iter().any(|t| self.reads.contains(t))
+  }
+}
+
+pub trait System: Send {
+  /// Components accessed by `run`. Systems returning `None` never run
+  /// in parallel with any other system.
+  fn access(&self) -> Option<Access> {
+    None
+  }
+
+  /// Structural changes like creating entities or adding components
+  /// have to be queued via `EntityManager::defer`.
+  fn run(&mut self, manager: &EntityManager, delta: Millis);
+}
+
+struct SystemEntry {
+  name:    &'static str,
+  system:  Box<System>,
+  access:  Option<Access>,
+  enabled: bool,
+  // Names of systems which have to run after/before this one
+  before:  Vec<&'static str>,
+  after:   Vec<&'static str>,
+}
+
+impl SystemEntry {
+  fn conflicts_with(&self, other: &SystemEntry) -> bool {
+    match (self.access.as_ref(), other.access.as_ref()) {
+      (Some(a), Some(b)) => a.conflicts_with(b),
+      _ => true,
+    }
+  }
+}
+
+/// Runs registered systems in an order satisfying their `before`/`after`
+/// constraints. Unconstrained systems run in registration order.
+///
+/// Systems are grouped into stages: systems in the same stage don't
+/// conflict in their component access and run in parallel on rayon's
+/// thread pool, unless parallel execution is disabled. Changes deferred
+/// by a stage's systems are applied before the next stage starts.
+pub struct Scheduler {
+  systems:  Vec<SystemEntry>,
+  // Cached execution stages (indices into `systems`)
+  stages:   Option<Vec<Vec<usize>>>,
+  parallel: bool,
+}
+
+impl Default for Scheduler {
+  fn default() -> Self {
+    Scheduler {
+      systems:  Vec::new(),
+      stages:   None,
+      parallel: true,
+    }
+  }
+}
+
+/// Returned by `Scheduler::add` to configure ordering constraints.
+pub struct SystemConfig<'a> {
+  scheduler: &'a mut Scheduler,
+  idx:       usize,
+}
+
+impl<'a> SystemConfig<'a> {
+  /// Runs this system before the system called `name`.
+  pub fn before(self, name: &'static str) -> Self {
+    self.scheduler.systems[self.idx].before.push(name);
+    self
+  }
+
+  /// Runs this system after the system called `name`.
+  pub fn after(self, name: &'static str) -> Self {
+    self.scheduler.systems[self.idx].after.push(name);
+    self
+  }
+}
+
+impl Scheduler {
+  pub fn new() -> Self {
+    Scheduler::default()
+  }
+
+  pub fn add<S>(&mut self, name: &'static str, system: S) -> SystemConfig
+    where S: System + 'static {
+    assert!(self.find(name).is_none(), "System {:?} already registered", name);
+
+    self.systems.push(SystemEntry {
+      name: name,
+      access: system.access(),
+      system: Box::new(system),
+      enabled: true,
+      before: Vec::new(),
+      after: Vec::new(),
+    });
+    self.stages = None;
+
+    let idx = self.systems.len() - 1;
+    SystemConfig {
+      scheduler: self,
+      idx: idx,
+    }
+  }
+
+  fn find(&self, name: &str) -> Option<usize> {
+    self.systems.iter().position(|entry| entry.name == name)
+  }
+
+  pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+    let idx = self.find(name).expect("Unknown system");
+    self.systems[idx].enabled = enabled;
+  }
+
+  pub fn is_enabled(&self, name: &str) -> bool {
+    self.find(name).map(|idx| self.systems[idx].enabled).unwrap_or(false)
+  }
+
+  /// Disabling parallel execution runs all systems one after another on
+  /// the calling thread, in a deterministic order. Useful for debugging.
+  pub fn set_parallel(&mut self, parallel: bool) {
+    self.parallel = parallel;
+  }
+
+  pub fn is_parallel(&self) -> bool {
+    self.parallel
+  }
+
+  /// Names of all systems, grouped by the stage they run in.
+  pub fn stage_names(&mut self) -> Vec<Vec<&'static str>> {
+    self.update_stages();
+    let systems = &self.systems;
+    self.stages.as_ref().unwrap().iter()
+      .map(|stage| stage.iter().map(|&idx| systems[idx].name).collect())
+      .collect()
+  }
+
+  fn update_stages(&mut self) {
+    if self.stages.is_some() {
+      return;
+    }
+
+    let count = self.systems.len();
+    let names: HashMap<&str, usize> = self.systems.iter()
+      .enumerate()
+      .map(|(idx, entry)| (entry.name, idx))
+      .collect();
+    let lookup = |name: &str| -> usize {
+      match names.get(name) {
+        Some(&idx) => idx,
+        None => panic!("Unknown system {:?} in ordering constraint", name),
+      }
+    };
+
+    // Edges point from a system to the systems which must run after it
+    let mut successors = vec![Vec::new(); count];
+    let mut predecessors = vec![Vec::new(); count];
+    for (idx, entry) in self.systems.iter().enumerate() {
+      for &name in &entry.before {
+        let succ = lookup(name);
+        successors[idx].push(succ);
+        predecessors[succ].push(idx);
+      }
+      for &name in &entry.after {
+        let pred = lookup(name);
+        successors[pred].push(idx);
+        predecessors[idx].push(pred);
+      }
+    }
+    let mut in_degree: Vec<usize> = predecessors.iter().map(|p| p.len()).collect();
+
+    // Kahn's algorithm, always picking the earliest registered system
+    // which is ready to keep the order stable
+    let mut order = Vec::with_capacity(count);
+    let mut done = vec![false; count];
+    while order.len() < count {
+      let next = (0..count).find(|&idx| !done[idx] && in_degree[idx] == 0)
+        .expect("Cyclic ordering constraints between systems");
+      done[next] = true;
+      order.push(next);
+      for &s in &successors[next] {
+        in_degree[s] -= 1;
+      }
+    }
+
+    // A system runs in a later stage than its predecessors and than all
+    // earlier systems it conflicts with
+    let mut stage_of = vec![0; count];
+    let mut stages: Vec<Vec<usize>> = Vec::new();
+    for (pos, &idx) in order.iter().enumerate() {
+      let mut stage = 0;
+      for &prev in &order[..pos] {
+        if predecessors[idx].contains(&prev)
+          || self.systems[idx].conflicts_with(&self.systems[prev]) {
+          stage = stage.max(stage_of[prev] + 1);
+        }
+      }
+      stage_of[idx] = stage;
+      if stages.len() <= stage {
+        stages.push(Vec::new());
+      }
+      stages[stage].push(idx);
+    }
+
+    self.stages = Some(stages);
+  }
+
+  pub fn run(&mut self, manager: &mut EntityManager, delta: Millis) {
+    self.update_stages();
+    let stages = self.stages.as_ref().unwrap();
+    for stage in stages {
+      {
+        let manager: &EntityManager = &*manager;
+        let mut systems: Vec<&mut SystemEntry> = self.systems.iter_mut()
+          .enumerate()
+          .filter(|&(idx, ref entry)| entry.enabled && stage.contains(&idx))
+          .map(|(_, entry)| entry)
+          .collect();
+
+        if !self.parallel || systems.len() < 2 {
+          for entry in systems {
+            entry.system.run(manager, delta);
+          }
+        } else {
+          rayon::scope(|scope| {
+            for entry in systems.drain(..) {
+              scope.spawn(move |_| entry.system.run(manager, delta));
+            }
+          });
+        }
+      }
+      manager.apply_deferred();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+  use super::super::components::Velocity;
+
+  struct Health(u32);
+
+  type Log = Arc<Mutex<Vec<&'static str>>>;
+
+  // Records its name in `log` when run
+  struct Recorder {
+    name:   &'static str,
+    access: Option<Access>,
+    log:    Log,
+  }
+
+  impl System for Recorder {
+    fn access(&self) -> Option<Access> {
+      self.access.clone()
+    }
+
+    fn run(&mut self, _manager: &EntityManager, _delta: Millis) {
+      self.log.lock().unwrap().push(self.name);
+    }
+  }
+
+  fn recorder(name: &'static str, access: Option<Access>, log: &Log) -> Recorder {
+    Recorder { name: name, access: access, log: log.clone() }
+  }
+
+  // Systems without declared access run alone, so there's one stage per
+  // system in execution order
+  fn exclusive(scheduler: &mut Scheduler, name: &'static str) -> SystemConfig {
+    let log = Log::default();
+    scheduler.add(name, recorder(name, None, &log))
+  }
+
+  #[test]
+  fn unconstrained_systems_keep_registration_order() {
+    let mut scheduler = Scheduler::new();
+    exclusive(&mut scheduler, "c");
+    exclusive(&mut scheduler, "a");
+    exclusive(&mut scheduler, "b");
+
+    assert_eq!(scheduler.stage_names(), vec![vec!["c"], vec!["a"], vec!["b"]]);
+  }
+
+  #[test]
+  fn before_and_after_constraints() {
+    let mut scheduler = Scheduler::new();
+    exclusive(&mut scheduler, "render");
+    exclusive(&mut scheduler, "late").after("render");
+    exclusive(&mut scheduler, "physics").before("render");
+    exclusive(&mut scheduler, "input").before("physics");
+    exclusive(&mut scheduler, "audio");
+
+    // Ready systems are picked in registration order
+    assert_eq!(scheduler.stage_names(),
+               vec![vec!["input"], vec!["physics"], vec!["render"], vec!["late"], vec!["audio"]]);
+  }
+
+  #[test]
+  fn adding_systems_updates_the_order() {
+    let mut scheduler = Scheduler::new();
+    exclusive(&mut scheduler, "a");
+    exclusive(&mut scheduler, "b");
+    assert_eq!(scheduler.stage_names(), vec![vec!["a"], vec!["b"]]);
+
+    exclusive(&mut scheduler, "c").before("a");
+    assert_eq!(scheduler.stage_names(), vec![vec!["c"], vec!["a"], vec!["b"]]);
+  }
+
+  #[test]
+  #[should_panic(expected = "Cyclic ordering constraints between systems")]
+  fn cyclic_constraints_panic() {
+    let mut scheduler = Scheduler::new();
+    exclusive(&mut scheduler, "a").before("b");
+    exclusive(&mut scheduler, "b").before("c");
+    exclusive(&mut scheduler, "c").before("a");
+    scheduler.stage_names();
+  }
+
+  #[test]
+  fn conflicting_access_gets_separate_stages() {
+    let log = Log::default();
+    let mut scheduler = Scheduler::new();
+    scheduler.add("write", recorder("write", Some(Access::new().write::<Health>()), &log));
+    scheduler.add("read", recorder("read", Some(Access::new().read::<Health>()), &log));
+    scheduler.add("both", recorder("both", Some(Access::new().read::<Velocity>().write::<Health>()), &log));
+
+    assert_eq!(scheduler.stage_names(), vec![vec!["write"], vec!["read"], vec!["both"]]);
+  }
+
+  #[test]
+  fn disjoint_access_shares_a_stage() {
+    let log = Log::default();
+    let mut scheduler = Scheduler::new();
+    scheduler.add("a", recorder("a", Some(Access::new().read::<Health>()), &log));
+    scheduler.add("b", recorder("b", Some(Access::new().read::<Health>()), &log));
+    scheduler.add("c", recorder("c", Some(Access::new().write::<Velocity>()), &log));
+    scheduler.add("d", recorder("d", None, &log));
+
+    assert_eq!(scheduler.stage_names(), vec![vec!["a", "b", "c"], vec!["d"]]);
+  }
+
+  fn run_once(parallel: bool, disabled: Option<&str>) -> (Vec<Vec<&'static str>>, Vec<&'static str>) {
+    let log = Log::default();
+    let mut scheduler = Scheduler::new();
+    scheduler.set_parallel(parallel);
+    scheduler.add("a", recorder("a", Some(Access::new().write::<Health>()), &log));
+    scheduler.add("b", recorder("b", Some(Access::new().read::<Velocity>()), &log));
+    scheduler.add("c", recorder("c", Some(Access::new().read::<Health>()), &log));
+    scheduler.add("d", recorder("d", None, &log));
+    if let Some(name) = disabled {
+      scheduler.set_enabled(name, false);
+    }
+
+    let mut manager = EntityManager::default();
+    scheduler.run(&mut manager, Millis(16.0));
+    let order = log.lock().unwrap().clone();
+    (scheduler.stage_names(), order)
+  }
+
+  #[test]
+  fn sequential_and_parallel_runs_respect_stages() {
+    let (stages, sequential) = run_once(false, None);
+    assert_eq!(stages, vec![vec!["a", "b"], vec!["c"], vec!["d"]]);
+    assert_eq!(sequential, vec!["a", "b", "c", "d"]);
+
+    let (parallel_stages, mut parallel) = run_once(true, None);
+    assert_eq!(parallel_stages, stages);
+    // Systems within a stage may finish in any order
+    parallel[..2].sort();
+    assert_eq!(parallel, sequential);
+  }
+
+  #[test]
+  fn disabled_systems_are_skipped() {
+    for &parallel in &[false, true] {
+      let (_, mut order) = run_once(parallel, Some("b"));
+      order.sort();
+      assert_eq!(order, vec!["a", "c", "d"]);
+    }
+  }
+
+  struct Spawner;
+
+  impl System for Spawner {
+    fn run(&mut self, manager: &EntityManager, _delta: Millis) {
+      manager.defer(|manager| {
+        let entity = manager.new_entity();
+        manager.insert(entity, Health(10));
+      });
+    }
+  }
+
+  struct Counter(Arc<Mutex<Vec<usize>>>);
+
+  impl System for Counter {
+    fn access(&self) -> Option<Access> {
+      Some(Access::new().read::<Health>())
+    }
+
+    fn run(&mut self, manager: &EntityManager, _delta: Millis) {
+      let count = manager.storage::<Health>().len();
+      self.0.lock().unwrap().push(count);
+    }
+  }
+
+  #[test]
+  fn deferred_changes_are_applied_after_each_stage() {
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let mut scheduler = Scheduler::new();
+    scheduler.add("counter", Counter(counts.clone()));
+    scheduler.add("spawner", Spawner).after("counter");
+
+    let mut manager = EntityManager::default();
+    manager.register::<Health>();
+    scheduler.run(&mut manager, Millis(16.0));
+    scheduler.run(&mut manager, Millis(16.0));
+
+    assert_eq!(*counts.lock().unwrap(), vec![0, 1]);
+    let healths = manager.storage::<Health>();
+    assert_eq!(manager.join(&*healths).map(|(_, health)| health.0).collect::<Vec<_>>(), vec![10, 10]);
+  }
+}
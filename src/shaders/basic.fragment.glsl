@@ -3,6 +3,7 @@
 in vec3 fragNormal;
 in vec3 fragVert;
 in vec2 fragUv;
+in vec4 fragTangent;
 
 layout(std140)
 uniform Uniforms {
@@ -13,12 +14,23 @@ uniform Uniforms {
   mat4 viewMatrix;
   mat4 projectionMatrix;
 
-  vec3 lightPosition;
   vec3 cameraPosition;
 };
 
+#include "lighting.glsl"
+
 uniform bool hasDiffuseTexture;
 uniform sampler2D diffuseTexture;
+uniform bool hasAmbientTexture;
+uniform sampler2D ambientTexture;
+uniform bool hasSpecularTexture;
+uniform sampler2D specularTexture;
+uniform bool hasShininessTexture;
+uniform sampler2D shininessTexture;
+uniform bool hasAlphaTexture;
+uniform sampler2D alphaTexture;
+uniform bool hasEmissionTexture;
+uniform sampler2D emissionTexture;
 
 uniform Material {
   vec4 ambient;
@@ -27,41 +39,93 @@ uniform Material {
   float shininess;
 };
 
-const float lightIntensity = 1.0;
 const float ambientIntensity = 0.1;
+// Transparency is alpha tested, transparent geometry isn't sorted
+const float alphaCutoff = 0.5;
 
 out vec4 color;
 
 vec3 ambientLighting();
-vec3 diffuseLighting(in vec3 N, in vec3 L);
-vec3 specularLighting(in vec3 N, in vec3 L, in vec3 V);
+vec3 diffuseLighting(in vec3 N, in vec3 L, in vec3 lightColor);
+vec3 specularLighting(in vec3 N, in vec3 L, in vec3 V, in vec3 lightColor);
+vec3 shade(in Light light, in vec3 P, in vec3 N, in vec3 V);
 
 void main() {
   // All in WorldSpace
   vec4 worldPosition   = modelMatrix * vec4(fragVert, 1.0);
-  vec3 normal          = normalize(mat3(normalMatrix)*fragNormal);
-  vec3 lightDirection  = normalize(lightPosition - worldPosition.xyz);
+  vec3 normal          = perturbNormal(normalize(mat3(normalMatrix)*fragNormal));
   vec3 cameraDirection = normalize(cameraPosition - worldPosition.xyz);
 
-  color.xyz = specularLighting(normal, lightDirection, cameraDirection)
-    + ambientLighting()
-    + diffuseLighting(normal, lightDirection);
-  color.a = 1.0;                // TODO
+  float alpha = 1.0;
+  if (hasAlphaTexture) {
+    alpha = texture(alphaTexture, fragUv).r;
+  }
+  if (alpha < alphaCutoff) {
+    discard;
+  }
+
+  color.xyz = ambientLighting();
+  for (uint i = 0u; i < lightCount; i++) {
+    color.xyz += shade(lights[i], worldPosition.xyz, normal, cameraDirection);
+  }
+  if (hasEmissionTexture) {
+    color.xyz += texture(emissionTexture, fragUv).rgb;
+  }
+  color.a = alpha;
+}
+
+vec3 shade(in Light light, in vec3 P, in vec3 N, in vec3 V) {
+  int kind = int(light.position.w);
+
+  if (kind == LIGHT_DIRECTIONAL) {
+    vec3 L = normalize(-light.direction.xyz);
+    vec3 lightColor = light.color.rgb * shadowFactor(light, P, N, L);
+    return specularLighting(N, L, V, lightColor)
+      + diffuseLighting(N, L, lightColor);
+  }
+
+  vec3 toLight = light.position.xyz - P;
+  float dist   = length(toLight);
+  vec3 L       = toLight / dist;
+
+  float attenuation = 1.0 / (light.attenuation.x
+                             + light.attenuation.y*dist
+                             + light.attenuation.z*dist*dist);
+  if (kind == LIGHT_SPOT) {
+    float theta = dot(-L, normalize(light.direction.xyz));
+    attenuation *= smoothstep(light.cone.y, light.cone.x, theta);
+  }
+  attenuation *= shadowFactor(light, P, N, L);
+
+  vec3 lightColor = light.color.rgb * attenuation;
+  return specularLighting(N, L, V, lightColor)
+    + diffuseLighting(N, L, lightColor);
 }
-vec3 specularLighting(in vec3 N, in vec3 L, in vec3 V) {
+
+vec3 specularLighting(in vec3 N, in vec3 L, in vec3 V, in vec3 lightColor) {
+  vec4 specular = int(hasSpecularTexture) * texture(specularTexture, fragUv)
+    + int(!hasSpecularTexture) * specular;
+  // The shininess map scales the exponent
+  float exponent = shininess;
+  if (hasShininessTexture) {
+    exponent *= texture(shininessTexture, fragUv).r;
+  }
+
   vec3 H = normalize(L + V);
-  float factor = max(pow(dot(N, H), shininess), 0.0);
-  return specular.xyz*lightIntensity*factor;
+  float factor = max(pow(max(dot(N, H), 0.0), exponent), 0.0);
+  return specular.xyz*lightColor*factor;
 }
 
-vec3 diffuseLighting(in vec3 N, in vec3 L) {
+vec3 diffuseLighting(in vec3 N, in vec3 L, in vec3 lightColor) {
   vec4 diffuse = int(hasDiffuseTexture) * texture(diffuseTexture, fragUv)
     + int(!hasDiffuseTexture) * diffuse;
 
   float factor = max(dot(N, L), 0.0);
-  return diffuse.xyz*lightIntensity*factor;
+  return diffuse.xyz*lightColor*factor;
 }
 
 vec3 ambientLighting() {
+  vec4 ambient = int(hasAmbientTexture) * texture(ambientTexture, fragUv)
+    + int(!hasAmbientTexture) * ambient;
   return ambient.xyz*ambientIntensity;
 }
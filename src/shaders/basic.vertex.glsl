@@ -3,21 +3,22 @@
 in vec3 position;
 in vec2 uv;
 in vec3 normal;
+in vec4 tangent;
 
 out vec3 fragVert;
 out vec3 fragNormal;
 out vec2 fragUv;
+out vec4 fragTangent;
 
 layout(std140)
 uniform Uniforms {
   uint pickingId;
 
   mat4 modelMatrix;
-  mat4 normalMatrix;            // might need layout(row_major)
+  mat4 normalMatrix;            // inverse-transpose, only mat3 part is used
   mat4 viewMatrix;
   mat4 projectionMatrix;
 
-  vec3 lightPosition;
   vec3 cameraPosition;
 };
 
@@ -25,6 +26,7 @@ void main() {
   fragNormal = normal;
   fragVert = position;
   fragUv = uv;
+  fragTangent = tangent;
 
   mat4 modelViewProject = projectionMatrix * viewMatrix * modelMatrix;
   gl_Position = modelViewProject * vec4(position, 1.0);
@@ -0,0 +1,124 @@
+// Lights, shadow mapping and normal mapping shared by the lit fragment
+// shaders. Included by `ResourceManager::compile_shader` after the
+// `Uniforms` block, as it needs `modelMatrix`, `fragUv` and
+// `fragTangent`.
+
+#define MAX_LIGHTS 8
+
+#define LIGHT_POINT       0
+#define LIGHT_DIRECTIONAL 1
+#define LIGHT_SPOT        2
+
+struct Light {
+  vec4 position;                // w: kind
+  vec4 direction;
+  vec4 color;                   // color * intensity
+  vec4 attenuation;             // constant, linear, quadratic
+  vec4 cone;                    // cos(inner), cos(outer)
+  vec4 shadow;                  // shadow map index (-1 for none), bias, cube, far
+  mat4 shadowMatrix;
+};
+
+layout(std140)
+uniform Lights {
+  uint lightCount;
+  Light lights[MAX_LIGHTS];
+};
+
+// Depth is compared by hand, glium doesn't support shadow samplers
+uniform sampler2DArray shadowMaps;
+uniform samplerCube pointShadowMap0;
+uniform samplerCube pointShadowMap1;
+uniform bool receiveShadows;
+
+uniform bool hasNormalTexture;
+uniform sampler2D normalTexture;
+
+// 1.0 if `depth` is lit in direction `direction` of point shadow map
+// `index`. Samplers can't be indexed dynamically in GLSL 3.30.
+float samplePointShadow(in int index, in vec3 direction, in float depth) {
+  float closest;
+  if (index == 0) {
+    closest = texture(pointShadowMap0, direction).r;
+  } else {
+    closest = texture(pointShadowMap1, direction).r;
+  }
+  return depth <= closest ? 1.0 : 0.0;
+}
+
+const vec3 pointShadowOffsets[20] = vec3[](
+  vec3( 1,  1,  1), vec3( 1, -1,  1), vec3(-1, -1,  1), vec3(-1,  1,  1),
+  vec3( 1,  1, -1), vec3( 1, -1, -1), vec3(-1, -1, -1), vec3(-1,  1, -1),
+  vec3( 1,  1,  0), vec3( 1, -1,  0), vec3(-1, -1,  0), vec3(-1,  1,  0),
+  vec3( 1,  0,  1), vec3(-1,  0,  1), vec3( 1,  0, -1), vec3(-1,  0, -1),
+  vec3( 0,  1,  1), vec3( 0, -1,  1), vec3( 0, -1, -1), vec3( 0,  1, -1)
+);
+
+// Cube map variant of `shadowFactor`, comparing linear distances
+float pointShadowFactor(in Light light, in vec3 P, in vec3 N, in vec3 L) {
+  vec3 fromLight = P - light.position.xyz;
+  float far      = light.shadow.w;
+  float dist     = length(fromLight);
+  if (dist > far) {
+    return 1.0;
+  }
+
+  float bias  = max(light.shadow.y * (1.0 - dot(N, L)), light.shadow.y * 0.1);
+  float depth = dist / far - bias;
+  // Filter radius grows with the distance to the light
+  float radius = 0.002 * dist;
+
+  int index = int(light.shadow.x);
+  float lit = 0.0;
+  for (int i = 0; i < 20; i++) {
+    lit += samplePointShadow(index, fromLight + pointShadowOffsets[i] * radius, depth);
+  }
+  return lit / 20.0;
+}
+
+// Fraction of light reaching P, filtered over 3x3 shadow map texels (PCF)
+float shadowFactor(in Light light, in vec3 P, in vec3 N, in vec3 L) {
+  if (!receiveShadows || light.shadow.x < 0.0) {
+    return 1.0;
+  }
+  if (light.shadow.z > 0.5) {
+    return pointShadowFactor(light, P, N, L);
+  }
+
+  vec4 lightSpace = light.shadowMatrix * vec4(P, 1.0);
+  vec3 coords = lightSpace.xyz / lightSpace.w * 0.5 + 0.5;
+  // Outside of the shadow map
+  if (coords.z > 1.0
+      || any(lessThan(coords.xy, vec2(0.0)))
+      || any(greaterThan(coords.xy, vec2(1.0)))) {
+    return 1.0;
+  }
+
+  // Surfaces at grazing angles need a larger bias
+  float bias = max(light.shadow.y * (1.0 - dot(N, L)), light.shadow.y * 0.1);
+  vec2 texel = 1.0 / vec2(textureSize(shadowMaps, 0).xy);
+
+  float lit = 0.0;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      vec2 uv = coords.xy + vec2(x, y) * texel;
+      float closest = texture(shadowMaps, vec3(uv, light.shadow.x)).r;
+      lit += coords.z - bias <= closest ? 1.0 : 0.0;
+    }
+  }
+  return lit / 9.0;
+}
+
+// Normal from the tangent space normal map, if there is one
+vec3 perturbNormal(in vec3 N) {
+  if (!hasNormalTexture) {
+    return N;
+  }
+
+  vec3 T = normalize(mat3(modelMatrix) * fragTangent.xyz);
+  T = normalize(T - dot(T, N) * N);
+  vec3 B = cross(N, T) * fragTangent.w;
+
+  vec3 mapped = texture(normalTexture, fragUv).xyz * 2.0 - 1.0;
+  return normalize(mat3(T, B, N) * mapped);
+}
@@ -0,0 +1,149 @@
+#version 330 core
+
+in vec3 fragNormal;
+in vec3 fragVert;
+in vec2 fragUv;
+in vec4 fragTangent;
+
+layout(std140)
+uniform Uniforms {
+  uint pickingId;
+
+  mat4 modelMatrix;
+  mat4 normalMatrix;
+  mat4 viewMatrix;
+  mat4 projectionMatrix;
+
+  vec3 cameraPosition;
+};
+
+#include "lighting.glsl"
+
+uniform bool hasBaseColorTexture;
+uniform sampler2D baseColorTexture;
+uniform bool hasMetallicTexture;
+uniform sampler2D metallicTexture;
+uniform bool hasRoughnessTexture;
+uniform sampler2D roughnessTexture;
+uniform bool hasEmissiveTexture;
+uniform sampler2D emissiveTexture;
+uniform bool hasOcclusionTexture;
+uniform sampler2D occlusionTexture;
+
+// Metallic-roughness material
+uniform PbrMaterial {
+  vec4 base_color;
+  vec4 emissive;
+  float metallic;
+  float roughness;
+  float occlusion;
+};
+
+const float PI = 3.14159265359;
+const float ambientIntensity = 0.03;
+
+out vec4 color;
+
+struct Surface {
+  vec3 baseColor;
+  float metallic;
+  float roughness;
+};
+
+vec3 brdf(in Surface surface, in vec3 N, in vec3 L, in vec3 V);
+vec3 shade(in Light light, in Surface surface, in vec3 P, in vec3 N, in vec3 V);
+
+void main() {
+  // All in WorldSpace
+  vec4 worldPosition   = modelMatrix * vec4(fragVert, 1.0);
+  vec3 normal          = perturbNormal(normalize(mat3(normalMatrix)*fragNormal));
+  vec3 cameraDirection = normalize(cameraPosition - worldPosition.xyz);
+
+  vec4 baseColor = base_color;
+  if (hasBaseColorTexture) {
+    baseColor *= texture(baseColorTexture, fragUv);
+  }
+
+  Surface surface;
+  surface.baseColor = baseColor.rgb;
+  surface.metallic  = metallic;
+  surface.roughness = roughness;
+  if (hasMetallicTexture) {
+    surface.metallic *= texture(metallicTexture, fragUv).r;
+  }
+  if (hasRoughnessTexture) {
+    surface.roughness *= texture(roughnessTexture, fragUv).r;
+  }
+  // Very low roughness makes highlights vanish
+  surface.roughness = clamp(surface.roughness, 0.04, 1.0);
+
+  float ao = 1.0;
+  if (hasOcclusionTexture) {
+    ao = mix(1.0, texture(occlusionTexture, fragUv).r, occlusion);
+  }
+
+  vec3 emission = emissive.rgb;
+  if (hasEmissiveTexture) {
+    emission *= texture(emissiveTexture, fragUv).rgb;
+  }
+
+  color.rgb = surface.baseColor * ambientIntensity * ao + emission;
+  for (uint i = 0u; i < lightCount; i++) {
+    color.rgb += shade(lights[i], surface, worldPosition.xyz, normal, cameraDirection);
+  }
+  color.a = baseColor.a;
+}
+
+vec3 shade(in Light light, in Surface surface, in vec3 P, in vec3 N, in vec3 V) {
+  int kind = int(light.position.w);
+
+  if (kind == LIGHT_DIRECTIONAL) {
+    vec3 L = normalize(-light.direction.xyz);
+    vec3 lightColor = light.color.rgb * shadowFactor(light, P, N, L);
+    return brdf(surface, N, L, V) * lightColor;
+  }
+
+  vec3 toLight = light.position.xyz - P;
+  float dist   = length(toLight);
+  vec3 L       = toLight / dist;
+
+  float attenuation = 1.0 / (light.attenuation.x
+                             + light.attenuation.y*dist
+                             + light.attenuation.z*dist*dist);
+  if (kind == LIGHT_SPOT) {
+    float theta = dot(-L, normalize(light.direction.xyz));
+    attenuation *= smoothstep(light.cone.y, light.cone.x, theta);
+  }
+  attenuation *= shadowFactor(light, P, N, L);
+
+  vec3 lightColor = light.color.rgb * attenuation;
+  return brdf(surface, N, L, V) * lightColor;
+}
+
+// Cook-Torrance with GGX distribution, Smith-Schlick geometry and
+// Schlick's Fresnel approximation, times N.L
+vec3 brdf(in Surface surface, in vec3 N, in vec3 L, in vec3 V) {
+  vec3 H = normalize(L + V);
+  float NdotL = max(dot(N, L), 0.0);
+  float NdotV = max(dot(N, V), 0.0001);
+  float NdotH = max(dot(N, H), 0.0);
+  float HdotV = max(dot(H, V), 0.0);
+
+  float a  = surface.roughness * surface.roughness;
+  float a2 = a * a;
+  float d  = NdotH * NdotH * (a2 - 1.0) + 1.0;
+  float D  = a2 / (PI * d * d);
+
+  float k = (surface.roughness + 1.0) * (surface.roughness + 1.0) / 8.0;
+  float G = (NdotV / (NdotV * (1.0 - k) + k)) * (NdotL / (NdotL * (1.0 - k) + k));
+
+  // Dielectrics reflect about 4% at normal incidence
+  vec3 F0 = mix(vec3(0.04), surface.baseColor, surface.metallic);
+  vec3 F  = F0 + (1.0 - F0) * pow(1.0 - HdotV, 5.0);
+
+  vec3 specular = D * G * F / (4.0 * NdotV * max(NdotL, 0.0001));
+  vec3 kd = (1.0 - F) * (1.0 - surface.metallic);
+  vec3 diffuse = kd * surface.baseColor / PI;
+
+  return (diffuse + specular) * NdotL;
+}
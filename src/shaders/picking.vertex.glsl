@@ -11,7 +11,6 @@ uniform Uniforms {
   mat4 viewMatrix;
   mat4 projectionMatrix;
 
-  vec3 lightPosition;
   vec3 cameraPosition;
 };
 
@@ -0,0 +1,11 @@
+#version 330 core
+
+in vec3 worldPosition;
+
+uniform vec3 lightPosition;
+uniform float farPlane;
+
+// Store the linear distance to the light, so all faces share one scale
+void main() {
+  gl_FragDepth = length(worldPosition - lightPosition) / farPlane;
+}
@@ -0,0 +1,14 @@
+#version 330 core
+
+in vec3 position;
+
+uniform mat4 lightMatrix;
+uniform mat4 modelMatrix;
+
+out vec3 worldPosition;
+
+void main() {
+  vec4 world = modelMatrix * vec4(position, 1.0);
+  worldPosition = world.xyz;
+  gl_Position = lightMatrix * world;
+}
@@ -0,0 +1,23 @@
+#version 330 core
+
+in vec2 fragUv;
+
+uniform samplerCube shadowMap;
+uniform int face;
+
+out vec4 color;
+
+// Direction through `uv` of a cube face, in the order +X -X +Y -Y +Z -Z
+vec3 faceDirection(int face, vec2 uv) {
+  if (face == 0) return vec3( 1.0, -uv.y, -uv.x);
+  if (face == 1) return vec3(-1.0, -uv.y,  uv.x);
+  if (face == 2) return vec3( uv.x,  1.0,  uv.y);
+  if (face == 3) return vec3( uv.x, -1.0, -uv.y);
+  if (face == 4) return vec3( uv.x, -uv.y,  1.0);
+  return vec3(-uv.x, -uv.y, -1.0);
+}
+
+void main() {
+  float depth = texture(shadowMap, faceDirection(face, fragUv)).r;
+  color = vec4(vec3(depth), 1.0);
+}
@@ -0,0 +1,10 @@
+#version 330 core
+
+out vec2 fragUv;
+
+// Fullscreen quad, drawn as a triangle strip of 4 vertices
+void main() {
+  vec2 corner = vec2(gl_VertexID % 2, gl_VertexID / 2);
+  fragUv = corner * 2.0 - 1.0;
+  gl_Position = vec4(fragUv, 0.0, 1.0);
+}
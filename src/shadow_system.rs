@@ -0,0 +1,182 @@
+use glium as gl;
+use glium::backend::Facade;
+
+use super::*;
+
+/// Maximum number of shadow casting directional and spot lights per
+/// frame. Additional lights are rendered without shadows.
+pub const MAX_SHADOW_MAPS: usize = 4;
+
+/// Maximum number of shadow casting point lights per frame, has to match
+/// the number of `pointShadowMap` samplers in the shaders.
+pub const MAX_POINT_SHADOW_MAPS: usize = 2;
+
+/// Shadow cube map to render for a point light.
+#[derive(Debug, Clone, Copy)]
+pub struct PointShadow {
+  pub position: Vector3<f32>,
+  pub settings: ShadowSettings,
+}
+
+/// Renders depth maps from the point of view of shadow casting lights:
+/// one layer of a depth texture array per directional or spot light and
+/// one cube map per point light.
+pub struct ShadowSystem {
+  maps: gl::texture::DepthTexture2dArray,
+  size: u32,
+
+  cube_maps:  Vec<gl::texture::DepthCubemap>,
+  cube_sizes: Vec<u32>,
+}
+
+impl ShadowSystem {
+  pub fn new<F: Facade>(f: &F, size: u32) -> Self {
+    let cube_size = ShadowSettings::default().resolution;
+    ShadowSystem {
+      maps: gl::texture::DepthTexture2dArray::empty(f, size, size, MAX_SHADOW_MAPS as u32).unwrap(),
+      size: size,
+
+      cube_maps:  (0..MAX_POINT_SHADOW_MAPS)
+        .map(|_| gl::texture::DepthCubemap::empty(f, cube_size).unwrap())
+        .collect(),
+      cube_sizes: vec![cube_size; MAX_POINT_SHADOW_MAPS],
+    }
+  }
+
+  pub fn size(&self) -> u32 {
+    self.size
+  }
+
+  pub fn maps(&self) -> &gl::texture::DepthTexture2dArray {
+    &self.maps
+  }
+
+  pub fn cube_map(&self, index: usize) -> &gl::texture::DepthCubemap {
+    &self.cube_maps[index]
+  }
+
+  fn depth_params<'a>() -> gl::DrawParameters<'a> {
+    gl::DrawParameters {
+      depth: gl::Depth {
+        test: gl::draw_parameters::DepthTest::IfLess,
+        write: true,
+        ..Default::default()
+      },
+      ..Default::default()
+    }
+  }
+
+  /// Renders `casters` (geometry and model matrix) into one layer per
+  /// entry in `light_matrices` and into one cube map per entry in
+  /// `point_lights`.
+  pub fn render(&mut self,
+                resources: &ResourceManager,
+                casters: &[(&Geometry, Matrix4<f32>)],
+                light_matrices: &[Matrix4<f32>],
+                point_lights: &[PointShadow]) {
+    assert!(light_matrices.len() <= MAX_SHADOW_MAPS);
+    assert!(point_lights.len() <= MAX_POINT_SHADOW_MAPS);
+
+    let params = Self::depth_params();
+    let facade = self.maps.get_context().clone();
+
+    {
+      let ref program = resources.programs["shadow"];
+
+      for (layer, light_matrix) in light_matrices.iter().enumerate() {
+        let level = self.maps.main_level().layer(layer as u32).unwrap();
+        let mut target = gl::framebuffer::SimpleFrameBuffer::depth_only(&facade, level).unwrap();
+        target.clear_depth(1.0);
+
+        for &(g, ref model_mat) in casters {
+          let uniforms = uniform! {
+            lightMatrix: light_matrix.as_uniform(),
+            modelMatrix: model_mat.as_uniform(),
+          };
+
+          for (_name, mesh) in resources.meshes[&g.geometry].meshes.iter() {
+            target.draw(&mesh.positions,
+                        &mesh.indices,
+                        program,
+                        &uniforms,
+                        &params)
+              .unwrap();
+          }
+        }
+      }
+    }
+
+    let ref program = resources.programs["shadow_cube"];
+
+    for (idx, light) in point_lights.iter().enumerate() {
+      // Resize on demand to the light's resolution
+      let resolution = light.settings.resolution;
+      if self.cube_sizes[idx] != resolution {
+        println!("Resizing point shadow map {} from {} to {}", idx, self.cube_sizes[idx], resolution);
+        self.cube_maps[idx] = gl::texture::DepthCubemap::empty(&facade, resolution).unwrap();
+        self.cube_sizes[idx] = resolution;
+      }
+
+      let matrices = cube_shadow_matrices(light.position, &light.settings);
+      for (face, light_matrix) in CUBE_FACES.iter().zip(matrices.iter()) {
+        let image = self.cube_maps[idx].main_level().image(*face);
+        let mut target = gl::framebuffer::SimpleFrameBuffer::depth_only(&facade, image).unwrap();
+        target.clear_depth(1.0);
+
+        for &(g, ref model_mat) in casters {
+          let uniforms = uniform! {
+            lightMatrix:   light_matrix.as_uniform(),
+            modelMatrix:   model_mat.as_uniform(),
+            lightPosition: light.position.as_uniform(),
+            farPlane:      light.settings.far,
+          };
+
+          for (_name, mesh) in resources.meshes[&g.geometry].meshes.iter() {
+            target.draw(&mesh.positions,
+                        &mesh.indices,
+                        program,
+                        &uniforms,
+                        &params)
+              .unwrap();
+          }
+        }
+      }
+    }
+  }
+
+  /// Draws the six faces of point shadow map `index` side by side in
+  /// the lower left corner of `surface`.
+  pub fn render_debug_faces<S>(&self, surface: &mut S, resources: &ResourceManager, index: usize)
+    where S: gl::Surface {
+    let ref program = resources.programs["shadow_debug"];
+    let sampler = gl::uniforms::Sampler::new(&self.cube_maps[index])
+      .magnify_filter(gl::uniforms::MagnifySamplerFilter::Nearest)
+      .minify_filter(gl::uniforms::MinifySamplerFilter::Nearest);
+
+    let size = 128;
+    for face in 0..CUBE_FACES.len() {
+      let params = gl::DrawParameters {
+        viewport: Some(gl::Rect {
+          left:   face as u32 * size,
+          bottom: 0,
+          width:  size,
+          height: size,
+        }),
+        ..Default::default()
+      };
+
+      let uniforms = uniform! {
+        shadowMap: sampler,
+        face:      face as i32,
+      };
+
+      // The quad's corners are generated in the vertex shader
+      surface.draw(gl::vertex::EmptyVertexAttributes { len: 4 },
+                   gl::index::NoIndices(gl::index::PrimitiveType::TriangleStrip),
+                   program,
+                   &uniforms,
+                   &params)
+        .unwrap();
+    }
+  }
+}
@@ -0,0 +1,279 @@
+use std::any::Any;
+use std::ops::{Deref, DerefMut, Index, IndexMut, RangeFull};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::slice;
+use std::u32;
+
+use super::components::EntityId;
+
+/// Dense per-entity storage, indexed by the entity's index. Grows on
+/// demand and is meant for data every entity has (flags, generations).
+pub struct EntityArray<T: Default>(Vec<T>);
+
+impl<T: Default> Default for EntityArray<T> {
+  fn default() -> Self {
+    EntityArray(Vec::new())
+  }
+}
+
+impl<T: Default> EntityArray<T> {
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  /// Makes sure `index` is a valid slot, filling new slots with
+  /// `T::default()`.
+  pub fn reserve_index(&mut self, index: u32) {
+    while self.0.len() <= index as usize {
+      self.0.push(Default::default());
+    }
+  }
+}
+
+impl<T: Default> Index<u32> for EntityArray<T> {
+  type Output = T;
+  fn index(&self, index: u32) -> &Self::Output {
+    self.0.index(index as usize)
+  }
+}
+
+impl<T: Default> IndexMut<u32> for EntityArray<T> {
+  fn index_mut(&mut self, index: u32) -> &mut T {
+    self.0.index_mut(index as usize)
+  }
+}
+
+impl<T: Default> Index<EntityId> for EntityArray<T> {
+  type Output = T;
+  fn index(&self, entity: EntityId) -> &Self::Output {
+    self.0.index(entity.index() as usize)
+  }
+}
+
+impl<T: Default> IndexMut<EntityId> for EntityArray<T> {
+  fn index_mut(&mut self, entity: EntityId) -> &mut T {
+    self.0.index_mut(entity.index() as usize)
+  }
+}
+
+impl<T: Default> Index<RangeFull> for EntityArray<T> {
+  type Output = [T];
+  fn index(&self, _idx: RangeFull) -> &Self::Output {
+    self.0.index(..)
+  }
+}
+
+const EMPTY_SLOT: u32 = u32::MAX;
+
+/// Sparse set holding one component type. Components are packed in a
+/// dense `Vec`, so memory scales with the number of entities actually
+/// having the component. Indexing an entity without the component
+/// panics, use `get` if that's expected.
+pub struct ComponentArray<T> {
+  // Entity index -> slot in `dense`
+  sparse:  Vec<u32>,
+  // Slot -> entity index
+  owners:  Vec<u32>,
+  dense:   Vec<T>,
+}
+
+impl<T> Default for ComponentArray<T> {
+  fn default() -> Self {
+    ComponentArray {
+      sparse: Vec::new(),
+      owners: Vec::new(),
+      dense:  Vec::new(),
+    }
+  }
+}
+
+impl<T> ComponentArray<T> {
+  pub fn len(&self) -> usize {
+    self.dense.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.dense.is_empty()
+  }
+
+  fn slot(&self, entity: EntityId) -> Option<usize> {
+    match self.sparse.get(entity.index() as usize) {
+      Some(&slot) if slot != EMPTY_SLOT => Some(slot as usize),
+      _ => None,
+    }
+  }
+
+  pub fn contains(&self, entity: EntityId) -> bool {
+    self.slot(entity).is_some()
+  }
+
+  pub fn get(&self, entity: EntityId) -> Option<&T> {
+    self.slot(entity).map(|slot| &self.dense[slot])
+  }
+
+  pub fn get_mut(&mut self, entity: EntityId) -> Option<&mut T> {
+    match self.slot(entity) {
+      Some(slot) => Some(&mut self.dense[slot]),
+      None => None,
+    }
+  }
+
+  /// Stores `value` for `entity`, returning the previous value. Only
+  /// the `EntityManager` may add or remove components, as it has to
+  /// keep the entity's flags in sync.
+  pub(crate) fn insert(&mut self, entity: EntityId, value: T) -> Option<T> {
+    use std::mem;
+    if let Some(slot) = self.slot(entity) {
+      return Some(mem::replace(&mut self.dense[slot], value));
+    }
+
+    let index = entity.index() as usize;
+    while self.sparse.len() <= index {
+      self.sparse.push(EMPTY_SLOT);
+    }
+    self.sparse[index] = self.dense.len() as u32;
+    self.owners.push(entity.index());
+    self.dense.push(value);
+    None
+  }
+
+  pub(crate) fn remove(&mut self, entity: EntityId) -> Option<T> {
+    self.slot(entity).map(|slot| {
+      // Move the last component into the freed slot
+      let last_owner = *self.owners.last().unwrap();
+      self.sparse[last_owner as usize] = slot as u32;
+      self.sparse[entity.index() as usize] = EMPTY_SLOT;
+      self.owners.swap_remove(slot);
+      self.dense.swap_remove(slot)
+    })
+  }
+
+  /// Indices of the entities having this component, in storage order.
+  pub fn owners(&self) -> &[u32] {
+    &self.owners
+  }
+
+  /// Iterates over all components together with their entity's index,
+  /// in storage order.
+  pub fn iter(&self) -> ComponentIter<T> {
+    ComponentIter {
+      owners: self.owners.iter(),
+      dense:  self.dense.iter(),
+    }
+  }
+}
+
+impl<T> Index<EntityId> for ComponentArray<T> {
+  type Output = T;
+  fn index(&self, entity: EntityId) -> &Self::Output {
+    match self.get(entity) {
+      Some(component) => component,
+      None => panic!("Entity {} doesn't have this component", entity),
+    }
+  }
+}
+
+impl<T> IndexMut<EntityId> for ComponentArray<T> {
+  fn index_mut(&mut self, entity: EntityId) -> &mut T {
+    match self.slot(entity) {
+      Some(slot) => &mut self.dense[slot],
+      None => panic!("Entity {} doesn't have this component", entity),
+    }
+  }
+}
+
+pub struct ComponentIter<'a, T: 'a> {
+  owners: slice::Iter<'a, u32>,
+  dense:  slice::Iter<'a, T>,
+}
+
+impl<'a, T> Iterator for ComponentIter<'a, T> {
+  type Item = (u32, &'a T);
+  fn next(&mut self) -> Option<Self::Item> {
+    match (self.owners.next(), self.dense.next()) {
+      (Some(&index), Some(component)) => Some((index, component)),
+      _ => None,
+    }
+  }
+}
+
+/// Anything which can be stored as a component. Components have to be
+/// `Send + Sync` so systems can run in parallel, which rules out types
+/// like `Rc` or `RefCell`. Wrap shared or interior mutable state in
+/// `Arc` and `Mutex` instead.
+pub trait Component: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Component for T {}
+
+/// Type-erased access to a component storage, so the `EntityManager`
+/// can handle storages of component types it doesn't know about.
+pub trait AnyStorage: Any + Send + Sync {
+  fn remove_entity(&mut self, entity: EntityId);
+  fn as_any(&self) -> &Any;
+}
+
+pub type StorageCell<T> = RwLock<ComponentArray<T>>;
+pub type ReadStorage<'a, T> = RwLockReadGuard<'a, ComponentArray<T>>;
+pub type WriteStorage<'a, T> = RwLockWriteGuard<'a, ComponentArray<T>>;
+
+impl<T: Component> AnyStorage for StorageCell<T> {
+  fn remove_entity(&mut self, entity: EntityId) {
+    self.get_mut().unwrap().remove(entity);
+  }
+
+  fn as_any(&self) -> &Any {
+    self
+  }
+}
+
+/// A single component, keeping its storage borrowed.
+pub struct ComponentRef<'a, T: 'a> {
+  storage: ReadStorage<'a, T>,
+  entity:  EntityId,
+}
+
+impl<'a, T> ComponentRef<'a, T> {
+  pub fn new(storage: ReadStorage<'a, T>, entity: EntityId) -> Self {
+    assert!(storage.contains(entity));
+    ComponentRef {
+      storage: storage,
+      entity: entity,
+    }
+  }
+}
+
+impl<'a, T> Deref for ComponentRef<'a, T> {
+  type Target = T;
+  fn deref(&self) -> &T {
+    &self.storage[self.entity]
+  }
+}
+
+/// A single mutable component, keeping its storage borrowed.
+pub struct ComponentRefMut<'a, T: 'a> {
+  storage: WriteStorage<'a, T>,
+  entity:  EntityId,
+}
+
+impl<'a, T> ComponentRefMut<'a, T> {
+  pub fn new(storage: WriteStorage<'a, T>, entity: EntityId) -> Self {
+    assert!(storage.contains(entity));
+    ComponentRefMut {
+      storage: storage,
+      entity: entity,
+    }
+  }
+}
+
+impl<'a, T> Deref for ComponentRefMut<'a, T> {
+  type Target = T;
+  fn deref(&self) -> &T {
+    &self.storage[self.entity]
+  }
+}
+
+impl<'a, T> DerefMut for ComponentRefMut<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.storage[self.entity]
+  }
+}
@@ -0,0 +1,130 @@
+use nalgebra as na;
+use nalgebra::{Vector3, Vector4};
+
+/// Per-vertex tangents for normal mapping, following MikkTSpace's
+/// conventions: triangle tangents are weighted by the triangle's angle
+/// at the vertex, orthogonalized against the vertex normal, and `w`
+/// holds the bitangent's sign (`bitangent = w * cross(normal, tangent)`).
+///
+/// Vertices without usable texture coordinates get an arbitrary tangent
+/// orthogonal to their normal.
+pub fn generate_tangents(positions: &[Vector3<f32>],
+                         normals: &[Vector3<f32>],
+                         uvs: &[[f32; 2]],
+                         indices: &[u32]) -> Vec<Vector4<f32>> {
+  assert_eq!(positions.len(), normals.len());
+  assert_eq!(positions.len(), uvs.len());
+
+  let mut tangents:   Vec<Vector3<f32>> = vec![na::zero(); positions.len()];
+  let mut bitangents: Vec<Vector3<f32>> = vec![na::zero(); positions.len()];
+
+  for tri in indices.chunks(3) {
+    if tri.len() < 3 {
+      break;
+    }
+    let idx = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+
+    let e1 = positions[idx[1]] - positions[idx[0]];
+    let e2 = positions[idx[2]] - positions[idx[0]];
+    let (du1, dv1) = (uvs[idx[1]][0] - uvs[idx[0]][0], uvs[idx[1]][1] - uvs[idx[0]][1]);
+    let (du2, dv2) = (uvs[idx[2]][0] - uvs[idx[0]][0], uvs[idx[2]][1] - uvs[idx[0]][1]);
+
+    let det = du1 * dv2 - du2 * dv1;
+    if det.abs() < 1e-12 {
+      // Degenerate texture coordinates
+      continue;
+    }
+    let r = 1.0 / det;
+    let tangent   = normalize_or_zero((e1 * dv2 - e2 * dv1) * r);
+    let bitangent = normalize_or_zero((e2 * du1 - e1 * du2) * r);
+
+    for corner in 0..3 {
+      let i = idx[corner];
+      let a = positions[idx[(corner + 1) % 3]] - positions[i];
+      let b = positions[idx[(corner + 2) % 3]] - positions[i];
+      let angle = angle_between(&a, &b);
+
+      tangents[i]   += tangent * angle;
+      bitangents[i] += bitangent * angle;
+    }
+  }
+
+  normals.iter().enumerate().map(|(i, normal)| {
+    // Gram-Schmidt
+    let t = tangents[i] - *normal * normal.dot(&tangents[i]);
+    let t = if t.norm() > 1e-6 { t.normalize() } else { orthogonal(normal) };
+    let w = if normal.cross(&t).dot(&bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+    Vector4::new(t.x, t.y, t.z, w)
+  }).collect()
+}
+
+fn normalize_or_zero(v: Vector3<f32>) -> Vector3<f32> {
+  if v.norm() > 1e-12 { v.normalize() } else { na::zero() }
+}
+
+fn angle_between(a: &Vector3<f32>, b: &Vector3<f32>) -> f32 {
+  let len = a.norm() * b.norm();
+  if len < 1e-12 {
+    return 0.0;
+  }
+  (a.dot(b) / len).max(-1.0).min(1.0).acos()
+}
+
+/// Some unit vector orthogonal to `n`.
+fn orthogonal(n: &Vector3<f32>) -> Vector3<f32> {
+  let axis = if n.x.abs() < 0.9 {
+    Vector3::new(1.0, 0.0, 0.0)
+  } else {
+    Vector3::new(0.0, 1.0, 0.0)
+  };
+  let t = axis - *n * n.dot(&axis);
+  if t.norm() > 1e-6 { t.normalize() } else { axis }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Unit quad in the XY plane facing +Z
+  fn quad(uvs: &[[f32; 2]], normal: Vector3<f32>) -> Vec<Vector4<f32>> {
+    let positions = [
+      Vector3::new(0.0, 0.0, 0.0),
+      Vector3::new(1.0, 0.0, 0.0),
+      Vector3::new(1.0, 1.0, 0.0),
+      Vector3::new(0.0, 1.0, 0.0),
+    ];
+    let normals = [normal; 4];
+    generate_tangents(&positions, &normals, uvs, &[0, 1, 2, 0, 2, 3])
+  }
+
+  fn assert_close(a: Vector4<f32>, b: Vector4<f32>) {
+    assert!((a - b).norm() < 1e-5, "{:?} != {:?}", a, b);
+  }
+
+  #[test]
+  fn tangents_follow_u() {
+    let uvs = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+    for tangent in quad(&uvs, Vector3::new(0.0, 0.0, 1.0)) {
+      assert_close(tangent, Vector4::new(1.0, 0.0, 0.0, 1.0));
+    }
+  }
+
+  #[test]
+  fn mirrored_uvs_flip_the_bitangent() {
+    let uvs = [[1.0, 0.0], [0.0, 0.0], [0.0, 1.0], [1.0, 1.0]];
+    for tangent in quad(&uvs, Vector3::new(0.0, 0.0, 1.0)) {
+      assert_close(tangent, Vector4::new(-1.0, 0.0, 0.0, -1.0));
+    }
+  }
+
+  #[test]
+  fn degenerate_uvs_get_an_orthogonal_tangent() {
+    let normal = Vector3::new(0.0, 0.6, 0.8);
+    for tangent in quad(&[[0.5, 0.5]; 4], normal) {
+      let t = Vector3::new(tangent.x, tangent.y, tangent.z);
+      assert!((t.norm() - 1.0).abs() < 1e-5, "{:?}", tangent);
+      assert!(t.dot(&normal).abs() < 1e-5, "{:?}", tangent);
+      assert!(tangent.w == 1.0 || tangent.w == -1.0);
+    }
+  }
+}
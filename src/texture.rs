@@ -0,0 +1,166 @@
+extern crate image;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use glium as gl;
+use super::resources::ResourceError;
+use glium::uniforms::{AsUniformValue, UniformValue, SamplerBehavior,
+                      SamplerWrapFunction, MinifySamplerFilter, MagnifySamplerFilter};
+
+/// How a texture gets loaded and sampled.
+#[derive(Debug, Clone, Copy)]
+pub struct TextureSettings {
+  /// Color textures are stored in sRGB, data like normal or roughness
+  /// maps must be linear.
+  pub srgb:       bool,
+  pub mipmaps:    bool,
+  pub wrap:       SamplerWrapFunction,
+  /// Needs `mipmaps` for the `*Mipmap*` filters.
+  pub minify:     MinifySamplerFilter,
+  pub magnify:    MagnifySamplerFilter,
+  pub anisotropy: u16,
+}
+
+impl TextureSettings {
+  /// Settings for data textures.
+  pub fn linear() -> Self {
+    TextureSettings { srgb: false, ..Default::default() }
+  }
+}
+
+impl Default for TextureSettings {
+  fn default() -> Self {
+    TextureSettings {
+      srgb:       true,
+      mipmaps:    true,
+      wrap:       SamplerWrapFunction::Repeat,
+      minify:     MinifySamplerFilter::LinearMipmapLinear,
+      magnify:    MagnifySamplerFilter::Linear,
+      anisotropy: 8,
+    }
+  }
+}
+
+enum TextureData {
+  Srgb(gl::texture::SrgbTexture2d),
+  Linear(gl::texture::Texture2d),
+}
+
+/// A 2D texture together with its sampler settings, usable directly in
+/// `uniform!`.
+pub struct Texture {
+  data: TextureData,
+  pub settings: TextureSettings,
+}
+
+impl Texture {
+  /// Loads `file`, picking its format (PNG, JPEG, TGA, BMP, HDR, ...) by
+  /// extension and falling back to guessing it from the contents. HDR
+  /// images are converted to 8 bit.
+  pub fn load<F>(facade: &F, file: &str, settings: TextureSettings) -> Result<Self, ResourceError>
+    where F: gl::backend::Facade {
+    let mut bytes = Vec::new();
+    File::open(file)
+      .and_then(|mut f| f.read_to_end(&mut bytes))
+      .map_err(|e| ResourceError::Io { file: file.to_string(), error: e })?;
+    let image = match format_from_extension(file) {
+      // The extension may be wrong, so try the contents as well
+      Some(format) => image::load_from_memory_with_format(&bytes, format)
+        .or_else(|e| image::load_from_memory(&bytes).map_err(|_| e)),
+      None => image::load_from_memory(&bytes),
+    };
+    let image = image
+      .map_err(|e| ResourceError::Parse { file: file.to_string(), message: e.to_string() })?
+      .to_rgba();
+
+    let size = image.dimensions();
+    println!("Loaded {} as image with size {:?}", file, size);
+    let image = gl::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(), size);
+    Self::new(facade, image, settings)
+  }
+
+  /// A 1x1 texture of `color`.
+  pub fn solid<F>(facade: &F, color: [u8; 4], settings: TextureSettings) -> Self
+    where F: gl::backend::Facade {
+    let image = gl::texture::RawImage2d::from_raw_rgba(color.to_vec(), (1, 1));
+    Self::new(facade, image, TextureSettings { mipmaps: false, ..settings }).unwrap()
+  }
+
+  /// Stands in for textures that failed to load.
+  pub fn fallback<F>(facade: &F) -> Self
+    where F: gl::backend::Facade {
+    Self::solid(facade, [255, 0, 255, 255], TextureSettings::default())
+  }
+
+  fn new<F>(facade: &F, image: gl::texture::RawImage2d<u8>, settings: TextureSettings) -> Result<Self, ResourceError>
+    where F: gl::backend::Facade {
+    let mipmaps = if settings.mipmaps {
+      gl::texture::MipmapsOption::AutoGeneratedMipmaps
+    } else {
+      gl::texture::MipmapsOption::NoMipmap
+    };
+
+    let data = if settings.srgb {
+      gl::texture::SrgbTexture2d::with_mipmaps(facade, image, mipmaps).map(TextureData::Srgb)
+    } else {
+      gl::texture::Texture2d::with_mipmaps(facade, image, mipmaps).map(TextureData::Linear)
+    };
+
+    data.map(|data| Texture { data: data, settings: settings })
+      .map_err(|e| ResourceError::Gl(format!("{:?}", e)))
+  }
+
+  fn sampler(&self) -> SamplerBehavior {
+    let s = &self.settings;
+    let minify = match (s.mipmaps, s.minify) {
+      (true, filter) => filter,
+      // Without mipmaps the texture would be incomplete
+      (false, MinifySamplerFilter::Nearest) |
+      (false, MinifySamplerFilter::NearestMipmapNearest) |
+      (false, MinifySamplerFilter::NearestMipmapLinear) => MinifySamplerFilter::Nearest,
+      (false, _) => MinifySamplerFilter::Linear,
+    };
+
+    SamplerBehavior {
+      wrap_function:  (s.wrap, s.wrap, s.wrap),
+      minify_filter:  minify,
+      magnify_filter: s.magnify,
+      max_anisotropy: s.anisotropy,
+      ..Default::default()
+    }
+  }
+}
+
+/// TGA has no signature, so it can only be detected by its extension.
+fn format_from_extension(file: &str) -> Option<image::ImageFormat> {
+  use self::image::ImageFormat;
+
+  let extension = Path::new(file).extension()
+    .and_then(|e| e.to_str())
+    .map(|e| e.to_lowercase());
+  match extension.as_ref().map(|e| e.as_str()) {
+    Some("png")                => Some(ImageFormat::PNG),
+    Some("jpg") | Some("jpeg") => Some(ImageFormat::JPEG),
+    Some("gif")                => Some(ImageFormat::GIF),
+    Some("webp")               => Some(ImageFormat::WEBP),
+    Some("ppm")                => Some(ImageFormat::PPM),
+    Some("tif") | Some("tiff") => Some(ImageFormat::TIFF),
+    Some("tga")                => Some(ImageFormat::TGA),
+    Some("bmp")                => Some(ImageFormat::BMP),
+    Some("ico")                => Some(ImageFormat::ICO),
+    Some("hdr")                => Some(ImageFormat::HDR),
+    _ => None,
+  }
+}
+
+impl<'a> AsUniformValue for &'a Texture {
+  fn as_uniform_value(&self) -> UniformValue {
+    let sampler = Some(self.sampler());
+    match self.data {
+      TextureData::Srgb(ref texture)   => UniformValue::SrgbTexture2d(texture, sampler),
+      TextureData::Linear(ref texture) => UniformValue::Texture2d(texture, sampler),
+    }
+  }
+}
@@ -0,0 +1,295 @@
+use std::ops::{Mul, MulAssign, Div, DivAssign, Rem};
+use std::time::Duration;
+
+custom_derive! {
+  /// Span of time in milliseconds. Negative values are allowed, e.g. as
+  /// the difference of two points in time.
+  #[derive(Debug, Copy, Clone, Default, PartialEq, PartialOrd,
+           NewtypeAdd, NewtypeSub, NewtypeNeg,
+           NewtypeAddAssign, NewtypeSubAssign)]
+  pub struct Millis(pub f32);
+}
+
+impl Millis {
+  pub fn from_seconds(seconds: f32) -> Self {
+    Millis(seconds * 1000.0)
+  }
+
+  pub fn from_millis(millis: f32) -> Self {
+    Millis(millis)
+  }
+
+  pub fn as_seconds(&self) -> f32 {
+    self.0 / 1000.0
+  }
+
+  pub fn as_millis(&self) -> f32 {
+    self.0
+  }
+
+  /// Converts to a `Duration`, clamping negative values to zero.
+  pub fn as_duration(&self) -> Duration {
+    let millis = self.0.max(0.0);
+    let secs = (millis / 1000.0).floor();
+    let nanos = ((millis - secs * 1000.0) * 1000000.0) as u32;
+    // Rounding may push the nanoseconds to a full second
+    Duration::new(secs as u64, nanos.min(999999999))
+  }
+
+  /// How many times `other` fits into `self`, e.g. the progress of a
+  /// timer.
+  pub fn ratio(&self, other: Millis) -> f32 {
+    self.0 / other.0
+  }
+
+  pub fn min(self, other: Millis) -> Millis {
+    Millis(self.0.min(other.0))
+  }
+
+  pub fn max(self, other: Millis) -> Millis {
+    Millis(self.0.max(other.0))
+  }
+}
+
+impl From<Duration> for Millis {
+  fn from(dur: Duration) -> Self {
+    Millis(dur.as_secs() as f32 * 1000.0
+           +
+           dur.subsec_nanos() as f32 / 1000000.0)
+  }
+}
+
+impl From<Millis> for Duration {
+  fn from(millis: Millis) -> Self {
+    millis.as_duration()
+  }
+}
+
+impl Mul<f32> for Millis {
+  type Output = Millis;
+
+  fn mul(self, rhs: f32) -> Millis {
+    Millis(self.0 * rhs)
+  }
+}
+
+impl Mul<Millis> for f32 {
+  type Output = Millis;
+
+  fn mul(self, rhs: Millis) -> Millis {
+    Millis(self * rhs.0)
+  }
+}
+
+impl MulAssign<f32> for Millis {
+  fn mul_assign(&mut self, rhs: f32) {
+    self.0 *= rhs;
+  }
+}
+
+impl Div<f32> for Millis {
+  type Output = Millis;
+
+  fn div(self, rhs: f32) -> Millis {
+    Millis(self.0 / rhs)
+  }
+}
+
+impl DivAssign<f32> for Millis {
+  fn div_assign(&mut self, rhs: f32) {
+    self.0 /= rhs;
+  }
+}
+
+/// Dividing two spans of time yields their ratio.
+impl Div<Millis> for Millis {
+  type Output = f32;
+
+  fn div(self, rhs: Millis) -> f32 {
+    self.ratio(rhs)
+  }
+}
+
+/// Remainder, e.g. to wrap a time around a period.
+impl Rem<Millis> for Millis {
+  type Output = Millis;
+
+  fn rem(self, rhs: Millis) -> Millis {
+    Millis(self.0 % rhs.0)
+  }
+}
+
+/// Simulation clock. Keeps track of game time, which can be scaled,
+/// paused and single-stepped independently of wall time.
+#[derive(Debug, Clone)]
+pub struct Time {
+  elapsed:       Millis,
+  real_elapsed:  Millis,
+  delta:         Millis,
+  scale:         f32,
+  paused:        bool,
+  pending_steps: u32,
+}
+
+impl Default for Time {
+  fn default() -> Self {
+    Time {
+      elapsed:       Millis(0.0),
+      real_elapsed:  Millis(0.0),
+      delta:         Millis(0.0),
+      scale:         1.0,
+      paused:        false,
+      pending_steps: 0,
+    }
+  }
+}
+
+impl Time {
+  pub fn new() -> Self {
+    Time::default()
+  }
+
+  /// Game time passed since the start of the simulation.
+  pub fn elapsed(&self) -> Millis {
+    self.elapsed
+  }
+
+  /// Wall time passed since the start of the simulation.
+  pub fn real_elapsed(&self) -> Millis {
+    self.real_elapsed
+  }
+
+  /// Game time passed during the last update step.
+  pub fn delta(&self) -> Millis {
+    self.delta
+  }
+
+  pub fn scale(&self) -> f32 {
+    self.scale
+  }
+
+  /// Factor between wall time and game time: values below 1.0 result
+  /// in slow motion.
+  pub fn set_scale(&mut self, scale: f32) {
+    assert!(scale >= 0.0, "Time scale must not be negative");
+    self.scale = scale;
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  pub fn pause(&mut self) {
+    self.paused = true;
+  }
+
+  pub fn resume(&mut self) {
+    self.paused = false;
+    self.pending_steps = 0;
+  }
+
+  pub fn toggle_pause(&mut self) {
+    if self.paused {
+      self.resume();
+    } else {
+      self.pause();
+    }
+  }
+
+  /// Advances a paused simulation by a single update step.
+  pub fn step(&mut self) {
+    if self.paused {
+      self.pending_steps += 1;
+    }
+  }
+
+  /// Advances the clock by `real_delta` of wall time and returns the
+  /// game time to simulate for this update step.
+  pub fn advance(&mut self, real_delta: Millis) -> Millis {
+    self.real_elapsed += real_delta;
+
+    let running = if self.paused && self.pending_steps > 0 {
+      self.pending_steps -= 1;
+      true
+    } else {
+      !self.paused
+    };
+
+    self.delta = if running {
+      real_delta * self.scale
+    } else {
+      Millis(0.0)
+    };
+    self.elapsed += self.delta;
+    self.delta
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn from_duration() {
+    assert_eq!(Millis::from(Duration::new(2, 500_000_000)), Millis(2500.0));
+    assert_eq!(Millis::from(Duration::from_millis(16)), Millis(16.0));
+    assert_eq!(Millis::from(Duration::new(0, 0)), Millis(0.0));
+  }
+
+  #[test]
+  fn duration_round_trip() {
+    let duration = Duration::new(2, 500_000_000);
+    assert_eq!(Millis::from(duration).as_duration(), duration);
+    assert_eq!(Duration::from(Millis(1500.0)), Duration::from_millis(1500));
+    assert_eq!(Millis::from_seconds(1.5).as_millis(), 1500.0);
+    assert_eq!(Millis(250.0).as_seconds(), 0.25);
+  }
+
+  #[test]
+  fn negative_durations_are_clamped() {
+    assert_eq!(Millis(-5.0).as_duration(), Duration::new(0, 0));
+    assert_eq!((Millis(1.0) - Millis(3.0)).as_duration(), Duration::new(0, 0));
+  }
+
+  #[test]
+  fn arithmetic() {
+    assert_eq!(Millis(10.0) * 2.5, Millis(25.0));
+    assert_eq!(2.0 * Millis(10.0), Millis(20.0));
+    assert_eq!(Millis(10.0) / 4.0, Millis(2.5));
+    assert_eq!(Millis(250.0) / Millis(1000.0), 0.25);
+    assert_eq!(Millis(2300.0) % Millis(1000.0), Millis(300.0));
+
+    let mut m = Millis(10.0);
+    m *= 3.0;
+    m /= 2.0;
+    m += Millis(5.0);
+    m -= Millis(1.0);
+    assert_eq!(m, Millis(19.0));
+  }
+
+  #[test]
+  fn ordering() {
+    assert!(Millis(1.0) < Millis(2.0));
+    assert!(Millis(-1.0) < Millis(0.0));
+    assert!(Millis(2.0) >= Millis(2.0));
+    assert_eq!(Millis(1.0).max(Millis(2.0)), Millis(2.0));
+    assert_eq!(Millis(1.0).min(Millis(2.0)), Millis(1.0));
+  }
+
+  #[test]
+  fn paused_time_only_advances_on_step() {
+    let mut time = Time::new();
+    time.set_scale(0.5);
+    assert_eq!(time.advance(Millis(10.0)), Millis(5.0));
+
+    time.pause();
+    assert_eq!(time.advance(Millis(10.0)), Millis(0.0));
+    time.step();
+    assert_eq!(time.advance(Millis(10.0)), Millis(5.0));
+    assert_eq!(time.advance(Millis(10.0)), Millis(0.0));
+
+    assert_eq!(time.elapsed(), Millis(10.0));
+    assert_eq!(time.real_elapsed(), Millis(40.0));
+  }
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+
+use super::*;
+
+/// Local transform of an entity, relative to its `Parent` if it has
+/// one. Fields are only accessible via setters so changes can be
+/// tracked and `WorldTransform` only gets recomputed when needed.
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+  translation: Vector3<f32>,
+  rotation:    UnitQuaternion<f32>,
+  scale:       Vector3<f32>,
+  dirty:       bool,
+
+  // State at the start of the current update step, for interpolation
+  previous_translation: Vector3<f32>,
+  previous_rotation:    UnitQuaternion<f32>,
+  previous_scale:       Vector3<f32>,
+}
+
+impl Default for Transform {
+  fn default() -> Self {
+    Transform::new(na::zero(), UnitQuaternion::identity(), Vector3::new(1.0, 1.0, 1.0))
+  }
+}
+
+impl Transform {
+  pub fn new(translation: Vector3<f32>, rotation: UnitQuaternion<f32>, scale: Vector3<f32>) -> Self {
+    Transform {
+      translation: translation,
+      rotation:    rotation,
+      scale:       scale,
+      dirty:       true,
+
+      previous_translation: translation,
+      previous_rotation:    rotation,
+      previous_scale:       scale,
+    }
+  }
+
+  pub fn translation(&self) -> Vector3<f32> {
+    self.translation
+  }
+
+  pub fn rotation(&self) -> UnitQuaternion<f32> {
+    self.rotation
+  }
+
+  pub fn scale(&self) -> Vector3<f32> {
+    self.scale
+  }
+
+  pub fn set_translation(&mut self, translation: Vector3<f32>) {
+    self.translation = translation;
+    self.dirty = true;
+  }
+
+  pub fn translate(&mut self, delta: Vector3<f32>) {
+    self.translation += delta;
+    self.dirty = true;
+  }
+
+  pub fn set_rotation(&mut self, rotation: UnitQuaternion<f32>) {
+    self.rotation = rotation;
+    self.dirty = true;
+  }
+
+  pub fn rotate(&mut self, rotation: UnitQuaternion<f32>) {
+    self.rotation *= rotation;
+    self.dirty = true;
+  }
+
+  pub fn set_scale(&mut self, scale: Vector3<f32>) {
+    self.scale = scale;
+    self.dirty = true;
+  }
+
+  /// Whether the transform changed since the last propagation.
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  pub fn matrix(&self) -> Matrix4<f32> {
+    Position(self.translation).as_matrix()
+      * Rotation(self.rotation).as_matrix()
+      * Scale(self.scale).as_matrix()
+  }
+
+  /// Remembers the current state as the previous one. Called at the
+  /// start of every update step.
+  pub fn store_previous(&mut self) {
+    self.previous_translation = self.translation;
+    self.previous_rotation    = self.rotation;
+    self.previous_scale       = self.scale;
+  }
+
+  /// Whether the transform changed during the last update step.
+  pub fn is_moving(&self) -> bool {
+    self.previous_translation != self.translation
+      || self.previous_rotation != self.rotation
+      || self.previous_scale != self.scale
+  }
+
+  /// Matrix between the previous (`alpha` = 0) and the current state
+  /// (`alpha` = 1).
+  pub fn interpolated_matrix(&self, alpha: f32) -> Matrix4<f32> {
+    let translation = self.previous_translation + (self.translation - self.previous_translation) * alpha;
+    let rotation    = self.previous_rotation.slerp(&self.rotation, alpha);
+    let scale       = self.previous_scale + (self.scale - self.previous_scale) * alpha;
+
+    Position(translation).as_matrix()
+      * Rotation(rotation).as_matrix()
+      * Scale(scale).as_matrix()
+  }
+}
+
+/// Attaches an entity to another one. The entity's `Transform` is then
+/// relative to its parent. Only created by `EntityManager::set_parent`,
+/// which makes sure the hierarchy stays free of cycles.
+#[derive(Debug)]
+pub struct Parent(EntityId);
+
+impl Parent {
+  pub fn entity(&self) -> EntityId {
+    self.0
+  }
+}
+
+/// Model matrix of an entity, including all of its parents' transforms.
+/// Maintained by `TransformSystem`.
+#[derive(Debug, Copy, Clone)]
+pub struct WorldTransform {
+  matrix: Matrix4<f32>,
+  normal: Matrix3<f32>,
+  // Parent used for `matrix`, to detect reparenting
+  parent: Option<EntityId>,
+  valid:  bool,
+}
+
+impl Default for WorldTransform {
+  fn default() -> Self {
+    WorldTransform {
+      matrix: Matrix4::identity(),
+      normal: Matrix3::identity(),
+      parent: None,
+      valid:  false,
+    }
+  }
+}
+
+impl WorldTransform {
+  pub fn new(matrix: Matrix4<f32>) -> Self {
+    WorldTransform {
+      matrix: matrix,
+      normal: normal_matrix(&matrix),
+      parent: None,
+      valid:  true,
+    }
+  }
+
+  pub fn matrix(&self) -> Matrix4<f32> {
+    self.matrix
+  }
+
+  /// Inverse-transpose of the model matrix, see `normal_matrix`.
+  pub fn normal_matrix(&self) -> Matrix3<f32> {
+    self.normal
+  }
+
+  pub fn translation(&self) -> Vector3<f32> {
+    Vector3::new(self.matrix[(0,3)], self.matrix[(1,3)], self.matrix[(2,3)])
+  }
+}
+
+impl EntityManager {
+  /// Modifies the `Transform` of `entity`, adding one if needed.
+  pub fn update_transform<F>(&mut self, entity: EntityId, f: F)
+    where F: FnOnce(&mut Transform) {
+    if !self.has::<Transform>(entity) {
+      self.insert(entity, Transform::default());
+    }
+    f(&mut *self.get_mut::<Transform>(entity).unwrap());
+  }
+
+  pub fn set_transform(&mut self, entity: EntityId, transform: Transform) {
+    self.insert(entity, transform);
+  }
+
+  pub fn transform(&self, entity: EntityId) -> Option<ComponentRef<Transform>> {
+    self.get::<Transform>(entity)
+  }
+
+  pub fn has_transform(&self, entity: EntityId) -> bool {
+    self.has::<Transform>(entity)
+  }
+
+  pub fn remove_transform(&mut self, entity: EntityId) -> Option<Transform> {
+    self.remove::<Transform>(entity)
+  }
+
+  pub fn set_position<P: Into<Position>>(&mut self, entity: EntityId, p: P) {
+    let p = p.into();
+    self.update_transform(entity, |t| t.set_translation(p.0));
+  }
+
+  pub fn set_scale<S: Into<Scale>>(&mut self, entity: EntityId, scale: S) {
+    let scale = scale.into();
+    self.update_transform(entity, |t| t.set_scale(scale.0));
+  }
+
+  pub fn set_rotation(&mut self, entity: EntityId, rot: Rotation) {
+    self.update_transform(entity, |t| t.set_rotation(rot.0));
+  }
+
+  /// Local position of `entity`.
+  pub fn position(&self, entity: EntityId) -> Option<Position> {
+    self.transform(entity).map(|t| Position(t.translation()))
+  }
+
+  /// Attaches `entity` to `parent`. Panics if this would create a cycle.
+  pub fn set_parent(&mut self, entity: EntityId, parent: EntityId) {
+    assert!(self.is_alive(parent), "Parent {} isn't alive", parent);
+
+    let mut ancestor = Some(parent);
+    while let Some(a) = ancestor {
+      assert!(a != entity, "Making {} a child of {} would create a cycle", entity, parent);
+      ancestor = self.parent(a);
+    }
+
+    self.insert(entity, Parent(parent));
+  }
+
+  pub fn parent(&self, entity: EntityId) -> Option<EntityId> {
+    self.get::<Parent>(entity).map(|p| p.0)
+  }
+
+  /// Detaches `entity` from its parent, making it a root again.
+  pub fn remove_parent(&mut self, entity: EntityId) -> Option<EntityId> {
+    self.remove::<Parent>(entity).map(|p| p.0)
+  }
+
+  pub fn children(&self, entity: EntityId) -> Vec<EntityId> {
+    let parents = self.storage::<Parent>();
+    self.join(&*parents)
+      .filter(|&(_, parent)| parent.0 == entity)
+      .map(|(child, _)| child)
+      .collect()
+  }
+
+  /// Deletes `entity` together with all of its descendants. Plain
+  /// `delete_entity` turns the children into roots instead.
+  pub fn delete_subtree(&mut self, entity: EntityId) {
+    for child in self.children(entity) {
+      self.delete_subtree(child);
+    }
+    self.delete_entity(entity);
+  }
+
+  pub fn world_transform(&self, entity: EntityId) -> Option<WorldTransform> {
+    self.get::<WorldTransform>(entity)
+      .and_then(|w| if w.valid { Some(*w) } else { None })
+  }
+
+  /// World transform of `entity` between the previous and the current
+  /// update step, see `Transform::interpolated_matrix`. Uses the cached
+  /// `WorldTransform` if neither the entity nor its ancestors moved.
+  pub fn interpolated_world_transform(&self, entity: EntityId, alpha: f32) -> Option<WorldTransform> {
+    if !self.has::<Transform>(entity) && !self.has::<Parent>(entity) {
+      return None;
+    }
+
+    let matrix = {
+      let transforms = self.storage::<Transform>();
+      let parents    = self.storage::<Parent>();
+
+      // Compose from the entity up to its root
+      let mut m = Matrix4::identity();
+      let mut moving = false;
+      let mut current = Some(entity);
+      while let Some(e) = current {
+        if let Some(t) = transforms.get(e) {
+          m = t.interpolated_matrix(alpha) * m;
+          moving = moving || t.is_moving();
+        }
+        current = parents.get(e).map(|p| p.0).and_then(|p| {
+          if self.is_alive(p) { Some(p) } else { None }
+        });
+      }
+
+      if !moving {
+        if let Some(world) = self.world_transform(entity) {
+          return Some(world);
+        }
+      }
+      m
+    };
+
+    Some(WorldTransform::new(matrix))
+  }
+
+  /// Position of `entity` in world space, as of the last transform
+  /// propagation. Falls back to its local translation.
+  pub fn world_position(&self, entity: EntityId) -> Option<Vector3<f32>> {
+    if let Some(world) = self.get::<WorldTransform>(entity) {
+      if world.valid {
+        return Some(world.translation());
+      }
+    }
+    self.transform(entity).map(|t| t.translation())
+  }
+}
+
+struct Propagation<'a> {
+  transforms: &'a ComponentArray<Transform>,
+  parents:    &'a ComponentArray<Parent>,
+  previous:   &'a ComponentArray<WorldTransform>,
+  // World matrix of every visited entity and whether it changed
+  visited:    HashMap<EntityId, (Matrix4<f32>, bool)>,
+}
+
+impl<'a> Propagation<'a> {
+  fn visit(&mut self, entity: EntityId) -> (Matrix4<f32>, bool) {
+    if let Some(&result) = self.visited.get(&entity) {
+      return result;
+    }
+
+    // Copy the storage references so lookups don't borrow `self`
+    let (transforms, parents, previous) = (self.transforms, self.parents, self.previous);
+
+    let parent = parents.get(entity).map(|p| p.0);
+    let previous = previous.get(entity);
+    let (parent_matrix, parent_changed) = match parent {
+      Some(parent) => self.visit(parent),
+      None => (Matrix4::identity(), false),
+    };
+    let transform = transforms.get(entity);
+
+    let changed = parent_changed
+      || transform.map(|t| t.dirty).unwrap_or(false)
+      || previous.map(|w| !w.valid || w.parent != parent).unwrap_or(true);
+
+    let result = if changed {
+      let local = transform.map(|t| t.matrix()).unwrap_or(Matrix4::identity());
+      (parent_matrix * local, true)
+    } else {
+      (previous.unwrap().matrix, false)
+    };
+    self.visited.insert(entity, result);
+    result
+  }
+}
+
+/// Updates the `WorldTransform` of every entity with a `Transform` or a
+/// `Parent`, only recomputing matrices of entities whose transform or
+/// ancestors changed. Entities losing both get their `WorldTransform`
+/// removed. Runs after all other systems, as it adds and removes
+/// components.
+pub struct TransformSystem;
+impl TransformSystem {
+  /// Snapshots all transforms before an update step, see
+  /// `Transform::store_previous`.
+  pub fn store_previous(manager: &EntityManager) {
+    let mut transforms = manager.storage_mut::<Transform>();
+    for (_, transform) in manager.join(&mut *transforms) {
+      transform.store_previous();
+    }
+  }
+
+  pub fn run(manager: &mut EntityManager) {
+    // Children of deleted entities become roots
+    let orphans: Vec<EntityId> = {
+      let parents = manager.storage::<Parent>();
+      manager.join(&*parents)
+        .filter(|&(_, parent)| !manager.is_alive(parent.0))
+        .map(|(entity, _)| entity)
+        .collect()
+    };
+    for entity in orphans {
+      manager.remove_parent(entity);
+    }
+
+    let missing: Vec<EntityId> = EntityManager::entity_iter(manager.entities(), FLAG_NONE)
+      .filter(|&entity| {
+        let flags = manager.flags(entity);
+        flags.intersects(FLAG_TRANSFORM | FLAG_PARENT) && !flags.contains(FLAG_WORLD_TRANSFORM)
+      })
+      .collect();
+    for entity in missing {
+      manager.insert(entity, WorldTransform::default());
+    }
+
+    // Entities that lost both their `Transform` and `Parent`
+    let stale: Vec<EntityId> = EntityManager::entity_iter(manager.entities(), FLAG_WORLD_TRANSFORM)
+      .filter(|&entity| !manager.flags(entity).intersects(FLAG_TRANSFORM | FLAG_PARENT))
+      .collect();
+    for entity in stale {
+      manager.remove::<WorldTransform>(entity);
+    }
+
+    let updates = {
+      let transforms       = manager.storage::<Transform>();
+      let parents          = manager.storage::<Parent>();
+      let world_transforms = manager.storage::<WorldTransform>();
+
+      let mut propagation = Propagation {
+        transforms: &*transforms,
+        parents:    &*parents,
+        previous:   &*world_transforms,
+        visited:    HashMap::new(),
+      };
+      for (entity, _) in manager.join(&*world_transforms) {
+        propagation.visit(entity);
+      }
+      propagation.visited
+    };
+
+    let mut transforms       = manager.storage_mut::<Transform>();
+    let mut world_transforms = manager.storage_mut::<WorldTransform>();
+    let parents              = manager.storage::<Parent>();
+    for (entity, world) in manager.join(&mut *world_transforms) {
+      if let Some(&(matrix, true)) = updates.get(&entity) {
+        world.matrix = matrix;
+        world.normal = normal_matrix(&matrix);
+        world.parent = parents.get(entity).map(|p| p.0);
+        world.valid  = true;
+      }
+    }
+    for (_, transform) in manager.join(&mut *transforms) {
+      transform.dirty = false;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn world_transform_follows_transform_and_parent() {
+    let mut manager = EntityManager::default();
+    let entity = manager.new_entity();
+    manager.set_position(entity, Vector3::new(1.0, 2.0, 3.0));
+    TransformSystem::run(&mut manager);
+    assert_eq!(manager.world_position(entity), Some(Vector3::new(1.0, 2.0, 3.0)));
+
+    manager.remove::<Transform>(entity);
+    TransformSystem::run(&mut manager);
+    assert!(!manager.has::<WorldTransform>(entity));
+    assert!(manager.world_transform(entity).is_none());
+    assert!(manager.world_position(entity).is_none());
+  }
+
+  #[test]
+  fn children_follow_moving_parents() {
+    let mut manager = EntityManager::default();
+    let parent = manager.new_entity();
+    let child  = manager.new_entity();
+    manager.set_position(parent, Vector3::new(1.0, 0.0, 0.0));
+    manager.set_position(child, Vector3::new(0.0, 2.0, 0.0));
+    manager.set_parent(child, parent);
+    TransformSystem::run(&mut manager);
+    assert_eq!(manager.world_position(child), Some(Vector3::new(1.0, 2.0, 0.0)));
+
+    manager.set_position(parent, Vector3::new(5.0, 0.0, 0.0));
+    manager.set_scale(parent, Vector3::new(2.0, 2.0, 2.0));
+    TransformSystem::run(&mut manager);
+    assert_eq!(manager.world_position(child), Some(Vector3::new(5.0, 4.0, 0.0)));
+    assert_eq!(manager.world_transform(child).unwrap().matrix(),
+               manager.world_transform(parent).unwrap().matrix()
+               * manager.transform(child).unwrap().matrix());
+  }
+
+  #[test]
+  fn reparenting_invalidates_world_transform() {
+    let mut manager = EntityManager::default();
+    let first  = manager.new_entity();
+    let second = manager.new_entity();
+    let child  = manager.new_entity();
+    manager.set_position(first, Vector3::new(1.0, 0.0, 0.0));
+    manager.set_position(second, Vector3::new(0.0, 0.0, 10.0));
+    manager.set_position(child, Vector3::new(0.0, 1.0, 0.0));
+    manager.set_parent(child, first);
+    TransformSystem::run(&mut manager);
+    assert_eq!(manager.world_position(child), Some(Vector3::new(1.0, 1.0, 0.0)));
+
+    // No transform changed, only the hierarchy
+    manager.set_parent(child, second);
+    TransformSystem::run(&mut manager);
+    assert_eq!(manager.world_position(child), Some(Vector3::new(0.0, 1.0, 10.0)));
+
+    manager.remove_parent(child);
+    TransformSystem::run(&mut manager);
+    assert_eq!(manager.world_position(child), Some(Vector3::new(0.0, 1.0, 0.0)));
+  }
+
+  #[test]
+  fn deleting_a_parent_makes_children_roots() {
+    let mut manager = EntityManager::default();
+    let parent = manager.new_entity();
+    let child  = manager.new_entity();
+    manager.set_position(parent, Vector3::new(1.0, 0.0, 0.0));
+    manager.set_position(child, Vector3::new(0.0, 1.0, 0.0));
+    manager.set_parent(child, parent);
+    TransformSystem::run(&mut manager);
+
+    manager.delete_entity(parent);
+    TransformSystem::run(&mut manager);
+    assert!(manager.is_alive(child));
+    assert_eq!(manager.parent(child), None);
+    assert_eq!(manager.world_position(child), Some(Vector3::new(0.0, 1.0, 0.0)));
+  }
+
+  #[test]
+  fn delete_subtree_removes_all_descendants() {
+    let mut manager = EntityManager::default();
+    let root       = manager.new_entity();
+    let child      = manager.new_entity();
+    let grandchild = manager.new_entity();
+    let sibling    = manager.new_entity();
+    manager.set_parent(child, root);
+    manager.set_parent(grandchild, child);
+    manager.set_parent(sibling, root);
+
+    manager.delete_subtree(child);
+    assert!(!manager.is_alive(child));
+    assert!(!manager.is_alive(grandchild));
+    assert!(manager.is_alive(root));
+    assert!(manager.is_alive(sibling));
+    assert_eq!(manager.children(root), vec![sibling]);
+  }
+
+  #[test]
+  #[should_panic(expected = "would create a cycle")]
+  fn set_parent_rejects_cycles() {
+    let mut manager = EntityManager::default();
+    let a = manager.new_entity();
+    let b = manager.new_entity();
+    manager.set_parent(b, a);
+    manager.set_parent(a, b);
+  }
+}
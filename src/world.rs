@@ -3,47 +3,103 @@ use glium as gl;
 
 use super::components::*;
 use super::render_system::*;
+use super::scheduler::*;
+use super::time::*;
+use super::transform::*;
 use super::{Millis, ResourceManager};
 
 pub struct World {
   pub resources: ResourceManager,
   pub entities: EntityManager,
+  pub scheduler: Scheduler,
+  pub time: Time,
 
-  // TODO: Make an Entity
-  pub light:          na::Vector3<f32>,
   pub mouse_position: Option<(u32, u32)>,
 
-  render_system:   RenderSystem,
-  picking_system:  PickingSystem,
+  quit: bool,
+
+  // `None` for headless worlds
+  graphics: Option<Graphics>,
+}
+
+/// The parts of a `World` requiring a GL context.
+struct Graphics {
+  render_system:  RenderSystem,
+  picking_system: PickingSystem,
+}
+
+impl Default for World {
+  fn default() -> Self {
+    World::new()
+  }
 }
 
 impl World {
-  pub fn new<F: gl::backend::Facade+Sized>(display: &F) -> Self {
+  /// Creates a headless world, which can be updated but not drawn
+  /// until a display gets attached via `attach_display`.
+  pub fn new() -> Self {
+    let mut scheduler = Scheduler::new();
+    scheduler.add("bob", BobSystem);
+    scheduler.add("velocity", VelocitySystem).after("bob");
+    scheduler.add("camera", CameraSystem).after("velocity");
+
     World {
       resources: ResourceManager::new(),
       entities: EntityManager::default(),
+      scheduler: scheduler,
+      time: Time::new(),
 
-      render_system: RenderSystem::new(display),
-      picking_system: PickingSystem::new(display, (800,600)),
-
-      light: na::Vector3::new(0.0, 0.0, 0.0),
       mouse_position: None,
+
+      quit: false,
+
+      graphics: None,
     }
   }
 
+  pub fn with_display<F: gl::backend::Facade+Sized>(display: &F) -> Self {
+    let mut world = World::new();
+    world.attach_display(display);
+    world
+  }
+
+  /// Creates the rendering and picking systems for `display`.
+  pub fn attach_display<F: gl::backend::Facade+Sized>(&mut self, display: &F) {
+    self.graphics = Some(Graphics {
+      render_system:  RenderSystem::new(display),
+      picking_system: PickingSystem::new(display, (800,600)),
+    });
+  }
+
+  pub fn is_headless(&self) -> bool {
+    self.graphics.is_none()
+  }
+
   fn current_camera(&self) -> Option<EntityId> {
-    EntityManager::entity_iter(&self.entities.entities, FLAG_CAMERA)
+    EntityManager::entity_iter(self.entities.entities(), FLAG_CAMERA)
       .next()
   }
 
-  fn uniforms(&self, (width,height): (u32, u32)) -> WorldUniforms {
+  fn uniforms(&self, (width,height): (u32, u32), alpha: f32) -> WorldUniforms {
     let camera = self.current_camera()
       .expect("Scene doesn't contain a camera!");
 
-    let camera_position = self.entities.positions[camera].0;
+    let interpolated_position = |entity| {
+      self.entities.interpolated_world_transform(entity, alpha)
+        .map(|world| world.translation())
+    };
+
+    let camera_position = interpolated_position(camera).unwrap_or(na::zero());
+    let camera_target   = {
+      let c = self.entities.camera(camera).unwrap();
+      c.tracking
+        .and_then(|target| if self.entities.is_alive(target) { Some(target) } else { None })
+        .and_then(|target| interpolated_position(target))
+        .unwrap_or(c.target)
+    };
     let camera_mat: na::Matrix4<f32> = 
       na::Isometry3::look_at_rh(&na::Point3::from_coordinates(camera_position),
-                                &na::Point3::from_coordinates(self.entities.cameras[camera].target),
+                                &na::Point3::from_coordinates(camera_target),
                                 &na::Vector3::new(0.0, 1.0, 0.0))
       .to_homogeneous();
 
@@ -57,45 +113,72 @@ impl World {
 
     WorldUniforms {
       projection_matrix: projection_mat,
-      light_position:    self.light,
       camera_matrix:     camera_mat,
       camera_position:   camera_position,
     }
   }
 
+  /// Asks the main loop to stop.
+  pub fn quit(&mut self) {
+    self.quit = true;
+  }
+
+  pub fn should_quit(&self) -> bool {
+    self.quit
+  }
+
   pub fn toggle_wireframe(&mut self) {
-    self.render_system.render_wireframe = !self.render_system.render_wireframe;
+    if let Some(ref mut graphics) = self.graphics {
+      graphics.render_system.render_wireframe = !graphics.render_system.render_wireframe;
+    }
+  }
+
+  pub fn toggle_shadow_debug(&mut self) {
+    if let Some(ref mut graphics) = self.graphics {
+      graphics.render_system.debug_shadow_faces = !graphics.render_system.debug_shadow_faces;
+    }
   }
 
+  /// Advances the simulation by `delta` of wall time, scaled (or not
+  /// advanced at all) according to `time`.
   pub fn update(&mut self, delta: Millis) {
+    let delta = self.time.advance(delta);
+    TransformSystem::store_previous(&self.entities);
+
     // Update picked entity
-    self.entities.picked_entity = self.mouse_position.and_then(|_| {
-      self.picking_system.read_picking_buffer()
-    });
+    let picked = match (self.mouse_position, self.graphics.as_mut()) {
+      (Some(_), Some(graphics)) => graphics.picking_system.read_picking_buffer(),
+      _ => None,
+    };
+    self.entities.picked_entity = picked.and_then(|id| self.entities.pickable_entity(id));
 
-    BobSystem::run(&mut self.entities, delta);
-    VelocitySystem::run(&mut self.entities, delta);
-    CameraSystem::run(&mut self.entities, delta);
+    self.scheduler.run(&mut self.entities, delta);
+    TransformSystem::run(&mut self.entities);
   }
 
-  pub fn draw<S>(&mut self, surface: &mut S)
+  /// Draws the world. `alpha` is the fraction of an update step passed
+  /// since the last call to `update`, used to interpolate transforms.
+  pub fn draw<S>(&mut self, surface: &mut S, alpha: f32)
     where S: gl::Surface {
     let surface_size = surface.get_dimensions();
+    let world_uniforms = self.uniforms(surface_size, alpha);
 
-    // Update PickingSystem's dimensions
-    self.picking_system.prepare(surface_size);
-    let mut picking_surface = self.picking_system.get_surface();
+    let graphics = self.graphics.as_mut()
+      .expect("Can't draw a headless World");
 
-    let world_uniforms = self.uniforms(surface_size);
+    // Update PickingSystem's dimensions
+    graphics.picking_system.prepare(surface_size);
+    let mut picking_surface = graphics.picking_system.get_surface();
 
-    self.render_system.render(&self.entities,
-                              surface,
-                              &mut picking_surface,
-                              &self.resources,
-                              &world_uniforms);
+    graphics.render_system.render(&self.entities,
+                                  surface,
+                                  &mut picking_surface,
+                                  &self.resources,
+                                  &world_uniforms,
+                                  alpha);
 
     if let Some(pos) = self.mouse_position {
-      self.picking_system.update(pos);
+      graphics.picking_system.update(pos);
     }
   }
 
@@ -104,7 +187,7 @@ impl World {
     use glium::glutin::*;
     for ev in events {
       match ev {
-        Event::Closed => return,
+        Event::Closed => self.quit(),
         Event::MouseInput(ElementState::Pressed, _) => {
           // camera_idx = (camera_idx+1) % camera_positions.len();
           // let pos = camera_positions[camera_idx];
@@ -125,8 +208,59 @@ impl World {
         Event::KeyboardInput(ElementState::Pressed, 25, _) => {
           self.toggle_wireframe();
         }
+        // Pause/resume with `p`, single-step with `n` while paused
+        Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::P)) => {
+          self.time.toggle_pause();
+          println!("Paused: {}", self.time.is_paused());
+        }
+        Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::N)) => {
+          self.time.step();
+        }
+        // Show point light shadow cube faces with `c`
+        Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::C)) => {
+          self.toggle_shadow_debug();
+        }
         _ => (),
       }
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn new_worlds_are_headless() {
+    let world = World::new();
+    assert!(world.is_headless());
+    assert!(!world.should_quit());
+  }
+
+  #[test]
+  fn updating_an_empty_world() {
+    let mut world = World::new();
+    world.update(Millis(16.0));
+    world.update(Millis(16.0));
+    assert!(world.is_headless());
+  }
+
+  #[test]
+  fn updating_moves_entities() {
+    let mut world = World::new();
+    let entity = world.entities.new_entity();
+    world.entities.set_position(entity, na::Vector3::new(0.0, 0.0, 0.0));
+    world.entities.insert(entity, Velocity {
+      linear: na::Vector3::new(1.0, 0.0, 0.0),
+      ..Default::default()
+    });
+    world.entities.insert(entity, Bob::new(Millis(1000.0), na::Vector3::new(0.0, 1.0, 0.0)));
+
+    world.update(Millis(100.0));
+    let position = world.entities.world_position(entity).unwrap();
+    assert!((position.x - 0.1).abs() < 1e-5, "{:?}", position);
+    assert!(position.y > 0.0, "{:?}", position);
+    assert_eq!(position.z, 0.0);
+    assert_eq!(world.time.elapsed(), Millis(100.0));
+  }
+}